@@ -1,23 +1,37 @@
-use bytes::{Bytes, BytesMut};
-use tokio::sync::mpsc;
+use bytes::Bytes;
+use tokio::sync::{mpsc, oneshot};
 
-use crate::error::Error;
+use crate::{error::Error, http::HeaderMap};
+
+#[derive(Debug)]
+enum Frame {
+    Data(Bytes),
+    // always the last frame
+    Trailers(HeaderMap),
+}
 
 #[derive(Debug)]
 enum Kind {
     Empty,
     Once(Bytes),
-    Channel(mpsc::Receiver<Result<Bytes, Error>>),
+    Channel(mpsc::Receiver<Result<Frame, Error>>),
 }
 
 #[derive(Debug)]
 pub struct Body {
     kind: Kind,
+    // told when the body is first read, dropped when it never is
+    demand: Option<oneshot::Sender<()>>,
+    trailers: Option<HeaderMap>,
 }
 
 impl Body {
     fn new(kind: Kind) -> Self {
-        Body { kind }
+        Body {
+            kind,
+            demand: None,
+            trailers: None,
+        }
     }
 
     pub fn empty() -> Self {
@@ -34,7 +48,51 @@ impl Body {
         (Sender::new(tx), Body::new(Kind::Channel(rx)))
     }
 
+    /// Length of the body when it is known up front.
+    pub fn size_hint(&self) -> Option<usize> {
+        match &self.kind {
+            Kind::Empty => Some(0),
+            Kind::Once(d) => Some(d.len()),
+            Kind::Channel(_) => None,
+        }
+    }
+
+    /// The body was given as bytes up front, rather than left empty.
+    pub(crate) fn is_once(&self) -> bool {
+        matches!(self.kind, Kind::Once(_))
+    }
+
+    /// A copy of a body known up front, a channel can only be read once.
+    pub(crate) fn try_clone(&self) -> Option<Body> {
+        match &self.kind {
+            Kind::Empty => Some(Body::empty()),
+            Kind::Once(d) => Some(Body::with_bytes(d.clone())),
+            Kind::Channel(_) => None,
+        }
+    }
+
+    /// Get notified when the body is first read.
+    pub(crate) fn on_demand(&mut self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.demand = Some(tx);
+        rx
+    }
+
+    pub(crate) fn take_once(&mut self) -> Option<Bytes> {
+        match std::mem::replace(&mut self.kind, Kind::Empty) {
+            Kind::Once(d) => Some(d),
+            kind => {
+                self.kind = kind;
+                None
+            }
+        }
+    }
+
     pub async fn data(&mut self) -> Result<Option<Bytes>, Error> {
+        if let Some(demand) = self.demand.take() {
+            let _ = demand.send(());
+        }
+
         match &mut self.kind {
             Kind::Empty => Ok(None),
             Kind::Once(d) => {
@@ -43,28 +101,51 @@ impl Body {
                 Ok(Some(data))
             }
             Kind::Channel(rx) => match rx.recv().await {
-                Some(Ok(d)) => Ok(Some(d)),
+                Some(Ok(Frame::Data(d))) => Ok(Some(d)),
+                Some(Ok(Frame::Trailers(trailers))) => {
+                    self.trailers = Some(trailers);
+                    self.kind = Kind::Empty;
+                    Ok(None)
+                }
                 Some(Err(err)) => Err(err),
                 None => Ok(None),
             },
         }
     }
+
+    /// Trailer fields sent after the body, empty when there are none. Data
+    /// not read yet is skipped.
+    pub async fn trailers(&mut self) -> Result<HeaderMap, Error> {
+        while self.data().await?.is_some() {}
+
+        Ok(self.trailers.take().unwrap_or_default())
+    }
 }
 
 pub struct Sender {
-    tx: mpsc::Sender<Result<Bytes, Error>>,
+    tx: mpsc::Sender<Result<Frame, Error>>,
 }
 
 impl Sender {
-    fn new(tx: mpsc::Sender<Result<Bytes, Error>>) -> Self {
+    fn new(tx: mpsc::Sender<Result<Frame, Error>>) -> Self {
         Sender { tx }
     }
 
     pub async fn send(&self, data: Result<Bytes, Error>) -> Result<(), Error> {
+        self.send_frame(data.map(Frame::Data)).await
+    }
+
+    /// Send trailer fields, nothing is sent after them. They are only
+    /// written out for a body framed with chunked transfer-coding.
+    pub async fn send_trailers(&self, trailers: HeaderMap) -> Result<(), Error> {
+        self.send_frame(Ok(Frame::Trailers(trailers))).await
+    }
+
+    async fn send_frame(&self, frame: Result<Frame, Error>) -> Result<(), Error> {
         self.tx
-            .send(data)
+            .send(frame)
             .await
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "").into())
+            .map_err(|_e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "").into())
     }
 
     pub async fn closed(&self) {
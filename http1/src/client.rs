@@ -0,0 +1,979 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bstr::ByteSlice;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::TcpStream,
+    sync::oneshot,
+    time::Instant,
+};
+
+use crate::{
+    body::{Body, Sender},
+    codec,
+    error::{Error, ErrorKind},
+    http::{
+        headers, ContentLength, Method, Request, Response, ResponseInfo, Scheme, StatusCode, Uri,
+    },
+    parser::{parse_response, ParseError, RawResponse},
+};
+
+const BUF_INIT_CAPACITY: usize = 4 * 1024 + 64;
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+const DEFAULT_MAX_IDLE_PER_HOST: usize = 32;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+struct Inner<IO> {
+    stream: BufWriter<IO>,
+    buffer: BytesMut,
+    // any byte of a response to the last request has arrived
+    answered: bool,
+    // when the previous response was read to its end
+    idle_at: Instant,
+}
+
+/// A client side HTTP/1.1 connection, sends one request at a time.
+///
+/// The response body is read in background, the connection becomes usable
+/// again once the previous body has been read to its end or dropped.
+pub struct Connection<IO> {
+    inner: Option<Inner<IO>>,
+    pending: Option<oneshot::Receiver<Option<Inner<IO>>>>,
+}
+
+impl<IO> Connection<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(io: IO) -> Self {
+        Connection {
+            inner: Some(Inner {
+                stream: BufWriter::new(io),
+                buffer: BytesMut::with_capacity(BUF_INIT_CAPACITY),
+                answered: false,
+                idle_at: Instant::now(),
+            }),
+            pending: None,
+        }
+    }
+
+    /// Returns true when the connection can not send another request.
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_none() && self.pending.is_none()
+    }
+
+    /// Returns true when a request can be sent without waiting for the
+    /// body of a previous response.
+    pub fn is_ready(&mut self) -> bool {
+        if let Some(pending) = &mut self.pending {
+            match pending.try_recv() {
+                Ok(inner) => {
+                    self.inner = inner;
+                    self.pending = None;
+                }
+                Err(oneshot::error::TryRecvError::Empty) => return false,
+                Err(oneshot::error::TryRecvError::Closed) => {
+                    self.pending = None;
+                }
+            }
+        }
+
+        self.inner.is_some()
+    }
+
+    /// When the connection became ready for another request, None while
+    /// the previous response body is still being read.
+    fn idle_since(&mut self) -> Option<Instant> {
+        match self.is_ready() {
+            true => self.inner.as_ref().map(|inner| inner.idle_at),
+            false => None,
+        }
+    }
+
+    /// A 101 Switching Protocols response ends the connection, upgrading
+    /// it to another protocol is not supported.
+    pub async fn send(&mut self, req: Request) -> Result<Response, Error> {
+        self.try_send(req).await.map_err(|(err, _unanswered)| err)
+    }
+
+    /// Like `send`, the error also tells whether it came before any response
+    /// bytes, so the request may be sent again on another connection.
+    async fn try_send(&mut self, req: Request) -> Result<Response, (Error, bool)> {
+        if let Some(pending) = self.pending.take() {
+            self.inner = pending.await.ok().flatten();
+        }
+
+        let mut inner = match self.inner.take() {
+            Some(inner) => inner,
+            None => {
+                let err =
+                    std::io::Error::new(std::io::ErrorKind::NotConnected, "connection closed");
+                return Err((err.into(), true));
+            }
+        };
+
+        let method = inner.write_request(req).await.map_err(|err| (err, true))?;
+
+        let mut info = ResponseInfo::new();
+        let mut resp = inner
+            .read_response_header(&method, &mut info)
+            .await
+            .map_err(|err| (err, !inner.answered))?;
+
+        let sender = match info.content_length {
+            ContentLength::None => None,
+            ContentLength::Sized(len) if inner.buffer.len() >= len => {
+                resp.body = Body::with_bytes(inner.buffer.split_to(len));
+                None
+            }
+            _ => {
+                let (sender, body) = Body::channel();
+                resp.body = body;
+                Some(sender)
+            }
+        };
+
+        match sender {
+            Some(sender) => {
+                let (done_tx, done_rx) = oneshot::channel();
+                self.pending = Some(done_rx);
+
+                tokio::spawn(async move {
+                    match inner.read_response_body(&info, &sender).await {
+                        Ok(()) if !info.should_close => {
+                            inner.idle_at = Instant::now();
+                            let _ = done_tx.send(Some(inner));
+                        }
+                        Ok(()) => {}
+                        // the body must not look complete when it is cut short
+                        Err(err) => {
+                            let _ = sender.send(Err(err)).await;
+                        }
+                    }
+
+                    // the connection is handed back before the body sees its end
+                    drop(sender);
+                });
+            }
+            None => {
+                // after 101 Switching Protocols the connection no longer speaks HTTP/1.1
+                if !info.should_close && resp.status_code != StatusCode::SWITCHING_PROTOCOLS {
+                    inner.idle_at = Instant::now();
+                    self.inner = Some(inner);
+                }
+            }
+        }
+
+        Ok(resp)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    scheme: Scheme,
+    host: String,
+    port: u16,
+}
+
+struct Pool {
+    idle: Mutex<HashMap<Key, Vec<Connection<TcpStream>>>>,
+    max_idle_per_host: usize,
+    idle_timeout: Duration,
+}
+
+impl Pool {
+    /// Connections still reading a response body are kept, their idle
+    /// time starts once the body is done.
+    fn is_expired(&self, conn: &mut Connection<TcpStream>, now: Instant) -> bool {
+        match conn.idle_since() {
+            Some(idle_at) => now - idle_at >= self.idle_timeout,
+            None => conn.is_closed(),
+        }
+    }
+
+    fn checkout(&self, key: &Key) -> Option<Connection<TcpStream>> {
+        let mut idle = self.idle.lock().unwrap();
+        let list = idle.get_mut(key)?;
+
+        let now = Instant::now();
+        list.retain_mut(|conn| !self.is_expired(conn, now));
+
+        // prefer the most recently used connection
+        let found = list.iter_mut().rposition(|conn| conn.is_ready());
+        let conn = found.map(|pos| list.remove(pos));
+
+        if list.is_empty() {
+            idle.remove(key);
+        }
+
+        conn
+    }
+
+    fn put(&self, key: Key, conn: Connection<TcpStream>) {
+        if conn.is_closed() {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        let list = idle.entry(key).or_default();
+
+        let now = Instant::now();
+        list.retain_mut(|conn| !self.is_expired(conn, now));
+
+        if list.len() < self.max_idle_per_host {
+            list.push(conn);
+        }
+    }
+}
+
+/// A HTTP/1.1 client, keeps idle keep-alive connections per
+/// (scheme, host, port) for reuse.
+#[derive(Clone)]
+pub struct Client {
+    pool: Arc<Pool>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Client::builder().build()
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder {
+            max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+
+    /// Send a request with an absolute-form uri, like `http://example.com/index.html`.
+    ///
+    /// A 101 Switching Protocols response is returned as is, but its
+    /// connection is closed, the client does not support upgrades.
+    pub async fn send(&self, req: Request) -> Result<Response, Error> {
+        let mut req = req;
+
+        let (key, authority, target) = destination(&req.uri)?;
+
+        if req.header_map.get(headers::HOST).is_none() {
+            req.header_map.set(headers::HOST, &authority);
+        }
+        req.uri = Uri::parse(&target)?;
+
+        let (mut conn, reused) = match self.pool.checkout(&key) {
+            Some(conn) => (conn, true),
+            None => (connect(&key).await?, false),
+        };
+
+        // the server may have closed an idle connection before seeing the request
+        let retry = match reused && req.method.is_idempotent() {
+            true => req.try_clone(),
+            false => None,
+        };
+
+        let resp = match (conn.try_send(req).await, retry) {
+            (Ok(resp), _) => resp,
+            (Err((_err, true)), Some(req)) => {
+                conn = connect(&key).await?;
+                conn.send(req).await?
+            }
+            (Err((err, _)), _) => return Err(err),
+        };
+
+        // the connection was dropped after 101, nothing is left to reuse
+        if resp.status_code != StatusCode::SWITCHING_PROTOCOLS {
+            self.pool.put(key, conn);
+        }
+
+        Ok(resp)
+    }
+}
+
+pub struct ClientBuilder {
+    max_idle_per_host: usize,
+    idle_timeout: Duration,
+}
+
+impl ClientBuilder {
+    /// Max idle connections kept for each host, 0 disables reuse.
+    pub fn max_idle_per_host(mut self, max: usize) -> Self {
+        self.max_idle_per_host = max;
+        self
+    }
+
+    /// Idle connections older than `timeout` are not reused.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> Client {
+        Client {
+            pool: Arc::new(Pool {
+                idle: Mutex::new(HashMap::new()),
+                max_idle_per_host: self.max_idle_per_host,
+                idle_timeout: self.idle_timeout,
+            }),
+        }
+    }
+}
+
+async fn connect(key: &Key) -> Result<Connection<TcpStream>, Error> {
+    if key.scheme != Scheme::HTTP {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "only http scheme is supported",
+        )
+        .into());
+    }
+
+    let host = key.host.trim_start_matches('[').trim_end_matches(']');
+    let stream = TcpStream::connect((host, key.port)).await?;
+    stream.set_nodelay(true)?;
+
+    Ok(Connection::new(stream))
+}
+
+/// Split an absolute-form uri into pool key, authority and origin-form target.
+fn destination(uri: &Uri) -> Result<(Key, Vec<u8>, Vec<u8>), Error> {
+    let (scheme, authority, host) = match (uri.scheme(), uri.authority(), uri.host()) {
+        (Some(scheme), Some(authority), Some(host)) => (scheme, authority, host),
+        _ => return Err(ParseError::BadUri.into()),
+    };
+
+    let port = uri.port().unwrap_or(match scheme {
+        Scheme::HTTP => 80,
+        Scheme::HTTPS => 443,
+    });
+
+    let host = String::from_utf8(host.to_ascii_lowercase()).map_err(|_| ParseError::BadUri)?;
+
+    let mut target = uri.path().to_vec();
+    if target.is_empty() {
+        target.push(b'/');
+    }
+    if let Some(query) = uri.query() {
+        target.push(b'?');
+        target.extend_from_slice(query);
+    }
+
+    Ok((Key { scheme, host, port }, authority.to_vec(), target))
+}
+
+impl<IO> Inner<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn write_request(&mut self, req: Request) -> Result<Method, Error> {
+        let mut req = req;
+
+        let mut length = 0;
+        let chunked = if req.header_map.get(headers::TRANSFER_ENCODING).is_some() {
+            // rfc9112 6.2, never sent along with a transfer-coding
+            req.header_map.remove(headers::CONTENT_LENGTH.as_bstr());
+            true
+        } else if let Some(len) = codec::declared_length(&req.header_map)? {
+            // a length other than the body's would desync the connection
+            if req.body.size_hint().is_some_and(|n| n != len) {
+                return Err(codec::length_mismatch());
+            }
+            length = len;
+            false
+        } else {
+            match req.body.size_hint() {
+                // no framing headers for a request without body
+                Some(0) => false,
+                Some(len) => {
+                    req.header_map
+                        .set(headers::CONTENT_LENGTH, len.to_string().as_bytes());
+                    length = len;
+                    false
+                }
+                None => {
+                    req.header_map
+                        .set(headers::TRANSFER_ENCODING, headers::CHUNKED);
+                    true
+                }
+            }
+        };
+
+        let data = req.header_buf();
+
+        if let Some(body) = req.body.take_once() {
+            let mut buf = BytesMut::with_capacity(data.len() + body.len());
+            buf.put_slice(&data);
+            buf.put_slice(&body);
+
+            self.stream.write_all(&buf).await?;
+        } else {
+            self.stream.write_all(&data).await?;
+
+            if chunked {
+                codec::write_chunked_body(&mut self.stream, &mut req.body).await?;
+            } else {
+                codec::write_sized_body(&mut self.stream, &mut req.body, length).await?;
+            }
+        }
+
+        self.stream.flush().await?;
+
+        Ok(req.method)
+    }
+
+    async fn read_response_header(
+        &mut self,
+        method: &Method,
+        info: &mut ResponseInfo,
+    ) -> Result<Response, Error> {
+        // bytes left over from before belong to the response as well
+        self.answered = !self.buffer.is_empty();
+
+        loop {
+            let mut rsp = RawResponse::new();
+            match parse_response(&self.buffer[..], &mut rsp) {
+                Ok(parsed) => {
+                    let ret = Response::from_raw_response(rsp, method, info);
+                    self.buffer.advance(parsed);
+
+                    // interim responses are skipped, except for 101 Switching Protocols
+                    match ret {
+                        Ok(resp) if resp.status_code.is_informational() => {
+                            if resp.status_code == StatusCode::SWITCHING_PROTOCOLS {
+                                return Ok(resp);
+                            }
+
+                            *info = ResponseInfo::new();
+                            continue;
+                        }
+                        ret => return ret,
+                    }
+                }
+                Err(ParseError::Incomplete) => {
+                    if self.buffer.len() > MAX_HEADER_SIZE {
+                        return Err(Error::new(ErrorKind::Protocol, ParseError::TooLarge));
+                    }
+                }
+                Err(err) => {
+                    return Err(Error::new(ErrorKind::Protocol, err));
+                }
+            }
+
+            codec::read_buf(&mut self.stream, &mut self.buffer).await?;
+            self.answered = true;
+        }
+    }
+
+    async fn read_response_body(
+        &mut self,
+        info: &ResponseInfo,
+        sender: &Sender,
+    ) -> Result<(), Error> {
+        let (stream, buffer) = (&mut self.stream, &mut self.buffer);
+
+        match info.content_length {
+            ContentLength::None => Ok(()),
+            ContentLength::Close => codec::read_close_body(stream, buffer, sender).await,
+            ContentLength::Sized(len) => codec::read_sized_body(stream, buffer, len, sender).await,
+            ContentLength::Chunked => codec::read_chunked_body(stream, buffer, None, sender).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bstr::ByteSlice;
+    use bytes::Bytes;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use crate::{
+        body::Body,
+        http::{Method, Request, Response, Scheme, Uri},
+        server::serve,
+    };
+
+    use super::{destination, Client, Connection, Key};
+
+    async fn collect(body: &mut Body) -> Vec<u8> {
+        let mut buf = Vec::new();
+        while let Some(d) = body.data().await.unwrap() {
+            buf.extend_from_slice(&d);
+        }
+        buf
+    }
+
+    fn get(uri: &str) -> Request {
+        Request::new(Method::GET, Uri::parse(uri.as_bytes()).unwrap())
+    }
+
+    async fn read_request_head<S: AsyncRead + Unpin>(server: &mut BufReader<S>) -> String {
+        let mut head = String::new();
+        loop {
+            let n = server.read_line(&mut head).await.unwrap();
+            if n == 0 || head.ends_with("\r\n\r\n") {
+                return head;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sized_and_chunked_response() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut server = BufReader::new(server);
+
+        tokio::spawn(async move {
+            let head = read_request_head(&mut server).await;
+            assert!(head.starts_with("GET /sized HTTP/1.1\r\n"), "{}", head);
+            server
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello")
+                .await
+                .unwrap();
+
+            let head = read_request_head(&mut server).await;
+            assert!(head.starts_with("GET /chunked HTTP/1.1\r\n"), "{}", head);
+            server
+                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n")
+                .await
+                .unwrap();
+
+            let head = read_request_head(&mut server).await;
+            assert!(head.starts_with("HEAD / HTTP/1.1\r\n"), "{}", head);
+            server
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let mut conn = Connection::new(client);
+
+        let mut resp = conn.send(get("/sized")).await.unwrap();
+        assert_eq!(resp.status_code, 200);
+        assert_eq!(collect(&mut resp.body).await, b"hello");
+
+        let mut resp = conn.send(get("/chunked")).await.unwrap();
+        assert_eq!(collect(&mut resp.body).await, b"hello world");
+
+        let mut req = get("/");
+        req.method = Method::HEAD;
+        let mut resp = conn.send(req).await.unwrap();
+        assert_eq!(collect(&mut resp.body).await, b"");
+        assert!(!conn.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_close_delimited_response() {
+        let (client, mut server) = tokio::io::duplex(64);
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let _ = server.read(&mut buf).await.unwrap();
+            server
+                .write_all(b"HTTP/1.0 200 OK\r\n\r\nuntil the connection closes")
+                .await
+                .unwrap();
+        });
+
+        let mut conn = Connection::new(client);
+
+        let mut resp = conn.send(get("/")).await.unwrap();
+        assert_eq!(
+            collect(&mut resp.body).await,
+            b"until the connection closes"
+        );
+
+        assert!(conn.send(get("/")).await.is_err());
+        assert!(conn.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_switching_protocols_response() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut server = BufReader::new(server);
+
+        tokio::spawn(async move {
+            let head = read_request_head(&mut server).await;
+            assert!(head.starts_with("GET /chat HTTP/1.1\r\n"), "{}", head);
+            server
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: chat\r\n\r\n")
+                .await
+                .unwrap();
+
+            // not HTTP/1.1 anymore, nothing may be written here
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await.unwrap();
+            assert!(buf.is_empty(), "{:?}", buf);
+        });
+
+        let mut conn = Connection::new(client);
+
+        let resp = conn.send(get("/chat")).await.unwrap();
+        assert_eq!(resp.status_code, 101);
+
+        assert!(conn.is_closed());
+        assert!(conn.send(get("/")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_length_mismatch() {
+        // known to be wrong before anything is written
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut conn = Connection::new(client);
+
+        let mut req = Request::new(Method::POST, Uri::parse(b"/").unwrap());
+        req.header_map.set(b"Content-Length", b"3");
+        req.body = Body::with_bytes("hello");
+        assert!(conn.send(req).await.is_err());
+        assert!(conn.is_closed());
+
+        drop(conn);
+        let mut buf = Vec::new();
+        server.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty(), "{:?}", buf.as_bstr());
+
+        // the body falls short of its length while being written
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut conn = Connection::new(client);
+
+        let (tx, body) = Body::channel();
+        tokio::spawn(async move {
+            tx.send(Ok(Bytes::from("hello"))).await.unwrap();
+        });
+        let mut req = Request::new(Method::POST, Uri::parse(b"/").unwrap());
+        req.header_map.set(b"Content-Length", b"10");
+        req.body = body;
+
+        let read = async move {
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let send = async move {
+            let ret = conn.send(req).await;
+            assert!(conn.is_closed());
+            ret
+        };
+        let (ret, buf) = tokio::join!(send, read);
+
+        assert!(ret.is_err());
+        assert!(buf.ends_with(b"\r\n\r\nhello"), "{:?}", buf.as_bstr());
+    }
+
+    #[tokio::test]
+    async fn test_request_body_with_server() {
+        let (client, server) = tokio::io::duplex(64);
+
+        tokio::spawn(serve(server, |mut req: Request| {
+            Box::pin(async move {
+                let mut resp = Response::new();
+                resp.body = Body::with_bytes(collect(&mut req.body).await);
+                resp
+            })
+        }));
+
+        let mut conn = Connection::new(client);
+
+        let mut req = Request::new(Method::POST, Uri::parse(b"/").unwrap());
+        req.body = Body::with_bytes("sized body");
+        let mut resp = conn.send(req).await.unwrap();
+        assert_eq!(collect(&mut resp.body).await, b"sized body");
+
+        let (tx, body) = Body::channel();
+        tokio::spawn(async move {
+            tx.send(Ok(Bytes::from("chunked"))).await.unwrap();
+            tx.send(Ok(Bytes::from(" body"))).await.unwrap();
+        });
+        let mut req = Request::new(Method::POST, Uri::parse(b"/").unwrap());
+        req.body = body;
+        let mut resp = conn.send(req).await.unwrap();
+        assert_eq!(collect(&mut resp.body).await, b"chunked body");
+    }
+
+    async fn listen(close: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                tokio::spawn(serve(stream, move |req: Request| {
+                    Box::pin(async move {
+                        let mut resp = Response::new();
+                        if close {
+                            resp.header_map.set(b"Connection", b"close");
+                        }
+                        if req.uri.as_bstr() == "/chunked" {
+                            let (tx, body) = Body::channel();
+                            tokio::spawn(async move {
+                                tx.send(Ok(Bytes::from("chunked"))).await.unwrap();
+                            });
+                            resp.body = body;
+                        } else {
+                            resp.body = Body::with_bytes("sized");
+                        }
+                        resp
+                    })
+                }));
+            }
+        });
+
+        (addr, accepted)
+    }
+
+    async fn fetch(client: &Client, addr: SocketAddr, path: &str) -> Vec<u8> {
+        let uri = format!("http://{}{}", addr, path);
+        let mut resp = client.send(get(&uri)).await.unwrap();
+        collect(&mut resp.body).await
+    }
+
+    /// answers every request head with `response`, closing silently after
+    /// the first one when `once`.
+    async fn listen_raw(response: &'static [u8], once: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut head = String::new();
+                        while !head.ends_with("\r\n\r\n") {
+                            if stream.read_line(&mut head).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                        }
+                        stream.write_all(response).await.unwrap();
+                        if once {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        (addr, accepted)
+    }
+
+    #[test]
+    fn test_destination() {
+        let dest = |uri: &[u8]| destination(&Uri::parse(uri)?);
+
+        let (key, authority, target) = dest(b"http://Example.com:8080/a?b=c").unwrap();
+        assert_eq!(
+            key,
+            Key {
+                scheme: Scheme::HTTP,
+                host: "example.com".to_string(),
+                port: 8080
+            }
+        );
+        assert_eq!(authority, b"Example.com:8080");
+        assert_eq!(target, b"/a?b=c");
+
+        let (key, _, target) = dest(b"https://[::1]?q").unwrap();
+        assert_eq!((key.host.as_str(), key.port), ("[::1]", 443));
+        assert_eq!(target, b"/?q");
+
+        assert!(dest(b"/index.html").is_err());
+        assert!(dest(b"ftp://example.com/").is_err());
+        assert!(dest(b"http://user@example.com/").is_err());
+        assert!(dest(b"http://example.com:http/").is_err());
+        assert!(dest(b"http://example.com/#frag").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_reuse_connection() {
+        let (addr, accepted) = listen(false).await;
+        let client = Client::new();
+
+        assert_eq!(fetch(&client, addr, "/").await, b"sized");
+        assert_eq!(fetch(&client, addr, "/chunked").await, b"chunked");
+        assert_eq!(fetch(&client, addr, "/").await, b"sized");
+
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_busy_connection() {
+        let (addr, accepted) = listen(false).await;
+        let client = Client::new();
+
+        // the first body is still unread, so another connection is needed
+        let uri = format!("http://{}/chunked", addr);
+        let mut resp = client.send(get(&uri)).await.unwrap();
+        assert_eq!(fetch(&client, addr, "/").await, b"sized");
+        assert_eq!(collect(&mut resp.body).await, b"chunked");
+
+        assert_eq!(fetch(&client, addr, "/").await, b"sized");
+        assert_eq!(fetch(&client, addr, "/").await, b"sized");
+
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_close() {
+        let (addr, accepted) = listen(true).await;
+        let client = Client::new();
+
+        assert_eq!(fetch(&client, addr, "/").await, b"sized");
+        assert_eq!(fetch(&client, addr, "/chunked").await, b"chunked");
+
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_switching_protocols() {
+        let (addr, accepted) = listen_raw(
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: chat\r\n\r\n",
+            false,
+        )
+        .await;
+        let client = Client::new();
+
+        let uri = format!("http://{}/chat", addr);
+        assert_eq!(client.send(get(&uri)).await.unwrap().status_code, 101);
+        assert_eq!(client.send(get(&uri)).await.unwrap().status_code, 101);
+
+        // the upgraded connection never goes back to the pool
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_retry_closed_connection() {
+        let (addr, accepted) =
+            listen_raw(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nsized", true).await;
+        let client = Client::new();
+
+        assert_eq!(fetch(&client, addr, "/").await, b"sized");
+        // the pooled connection was closed by the server, GET is sent again
+        assert_eq!(fetch(&client, addr, "/").await, b"sized");
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // POST is not idempotent, the failure is returned as is
+        let uri = format!("http://{}/", addr);
+        let mut req = Request::new(Method::POST, Uri::parse(uri.as_bytes()).unwrap());
+        req.body = Body::with_bytes("sized body");
+        assert!(client.send(req).await.is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_no_retry_after_interim() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let mut stream = BufReader::new(stream);
+                read_request_head(&mut stream).await;
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nsized")
+                    .await
+                    .unwrap();
+
+                // answered with an interim response only, the request was seen
+                read_request_head(&mut stream).await;
+                stream
+                    .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let client = Client::new();
+        assert_eq!(fetch(&client, addr, "/").await, b"sized");
+
+        let uri = format!("http://{}/", addr);
+        assert!(client.send(get(&uri)).await.is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_idle_after_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        if read_request_head(&mut stream).await.is_empty() {
+                            return;
+                        }
+                        stream
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n")
+                            .await
+                            .unwrap();
+                        // the body takes longer than the idle timeout
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                        stream.write_all(b"slow").await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let client = Client::builder()
+            .idle_timeout(Duration::from_secs(5))
+            .build();
+        assert_eq!(fetch(&client, addr, "/").await, b"slow");
+        assert_eq!(fetch(&client, addr, "/").await, b"slow");
+
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_pool_limits() {
+        let (addr, accepted) = listen(false).await;
+
+        let client = Client::builder().max_idle_per_host(0).build();
+        fetch(&client, addr, "/").await;
+        fetch(&client, addr, "/").await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        let client = Client::builder().idle_timeout(Duration::ZERO).build();
+        fetch(&client, addr, "/").await;
+        fetch(&client, addr, "/").await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 4);
+    }
+}
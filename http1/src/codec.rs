@@ -0,0 +1,500 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    select,
+    time::{Instant, Sleep},
+};
+
+use crate::{
+    body::{Body, Sender},
+    error::Error,
+    http::{headers, put_header_map, HeaderMap},
+    parser::{parse_chunk_size, parse_trailers, ParseError, FORBIDDEN_TRAILERS},
+};
+
+const MAX_CHUNK_LINE_SIZE: usize = 1024;
+const MAX_TRAILER_SIZE: usize = 4 * 1024;
+
+/// Read more data into buffer, reaching EOF here means the peer
+/// closed the connection in the middle of a message.
+pub(crate) async fn read_buf<R>(stream: &mut R, buffer: &mut BytesMut) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let n = stream.read_buf(buffer).await?;
+    if n == 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "").into());
+    }
+
+    Ok(())
+}
+
+/// Fail a read that gets no data within `timeout`, the time spent between
+/// reads is not counted.
+pub(crate) struct ReadTimeout<R> {
+    inner: R,
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    armed: bool,
+}
+
+impl<R> ReadTimeout<R> {
+    pub(crate) fn new(inner: R, timeout: Duration) -> Self {
+        ReadTimeout {
+            inner,
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            armed: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ReadTimeout<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+
+        if !this.armed {
+            this.sleep.as_mut().reset(Instant::now() + this.timeout);
+            this.armed = true;
+        }
+
+        if let Poll::Ready(ret) = Pin::new(&mut this.inner).poll_read(cx, buf) {
+            this.armed = false;
+            return Poll::Ready(ret);
+        }
+
+        match this.sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                this.armed = false;
+                Poll::Ready(Err(std::io::ErrorKind::TimedOut.into()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+pub(crate) async fn read_sized_body<R>(
+    stream: &mut R,
+    buffer: &mut BytesMut,
+    len: usize,
+    sender: &Sender,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut need = len;
+
+    loop {
+        let mut done = false;
+
+        let to_send = if buffer.len() >= need {
+            done = true;
+            buffer.split_to(need).freeze()
+        } else {
+            need -= buffer.len();
+            buffer.split().freeze()
+        };
+
+        select! {
+            _closed = sender.closed() => {
+                // when body been dropped, just drop rest body
+                if done {
+                    return Ok(());
+                }
+                break;
+            }
+
+            _ = sender.send(Ok(to_send)) => {
+                if done {
+                    return Ok(());
+                }
+            }
+        }
+
+        read_buf(stream, buffer).await?;
+    }
+
+    // consume body
+    loop {
+        if buffer.len() >= need {
+            buffer.advance(need);
+            break;
+        } else {
+            buffer.advance(buffer.len());
+        };
+
+        read_buf(stream, buffer).await?;
+    }
+
+    Ok(())
+}
+
+/// Read a chunked body, failing once its size goes over `limit`.
+pub(crate) async fn read_chunked_body<R>(
+    stream: &mut R,
+    buffer: &mut BytesMut,
+    limit: Option<usize>,
+    sender: &Sender,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    // when body been dropped, keep decoding but drop the data
+    let mut discard = false;
+    let mut total = 0u64;
+
+    loop {
+        let size = read_chunk_size(stream, buffer).await?;
+        if size == 0 {
+            break;
+        }
+
+        total = total.saturating_add(size);
+        if limit.is_some_and(|limit| total > limit as u64) {
+            return Err(ParseError::ContentTooLarge.into());
+        }
+
+        let mut need = size;
+        while need > 0 {
+            if buffer.is_empty() {
+                read_buf(stream, buffer).await?;
+            }
+
+            let n = std::cmp::min(need, buffer.len() as u64) as usize;
+            let to_send = buffer.split_to(n).freeze();
+            need -= n as u64;
+
+            if discard {
+                continue;
+            }
+
+            select! {
+                _closed = sender.closed() => {
+                    discard = true;
+                }
+
+                ret = sender.send(Ok(to_send)) => {
+                    discard = ret.is_err();
+                }
+            }
+        }
+
+        // chunk-data must be followed by CRLF
+        while buffer.len() < 2 {
+            read_buf(stream, buffer).await?;
+        }
+        if buffer[..2] != b"\r\n"[..] {
+            return Err(ParseError::BadData.into());
+        }
+        buffer.advance(2);
+    }
+
+    let trailers = read_chunk_trailers(stream, buffer).await?;
+    if !discard && !trailers.is_empty() {
+        let _ = sender.send_trailers(trailers).await;
+    }
+
+    Ok(())
+}
+
+async fn read_chunk_size<R>(stream: &mut R, buffer: &mut BytesMut) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        match parse_chunk_size(&buffer[..]) {
+            Ok((parsed, size)) => {
+                buffer.advance(parsed);
+                return Ok(size);
+            }
+            Err(ParseError::Incomplete) => {
+                if buffer.len() > MAX_CHUNK_LINE_SIZE {
+                    return Err(ParseError::TooLarge.into());
+                }
+            }
+            Err(err) => {
+                return Err(err.into());
+            }
+        }
+
+        read_buf(stream, buffer).await?;
+    }
+}
+
+async fn read_chunk_trailers<R>(stream: &mut R, buffer: &mut BytesMut) -> Result<HeaderMap, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let mut trailers = Vec::new();
+        match parse_trailers(&buffer[..], &mut trailers) {
+            Ok(parsed) => {
+                let mut header_map = HeaderMap::new();
+                for h in trailers {
+                    header_map.append(h.name, h.value);
+                }
+
+                buffer.advance(parsed);
+                return Ok(header_map);
+            }
+            Err(ParseError::Incomplete) => {
+                if buffer.len() > MAX_TRAILER_SIZE {
+                    return Err(ParseError::TooLarge.into());
+                }
+            }
+            Err(err) => {
+                return Err(err.into());
+            }
+        }
+
+        read_buf(stream, buffer).await?;
+    }
+}
+
+/// Read a close-delimited body, rfc9112 6.3, only responses may be
+/// delimited this way.
+pub(crate) async fn read_close_body<R>(
+    stream: &mut R,
+    buffer: &mut BytesMut,
+    sender: &Sender,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    // when body been dropped, keep reading until EOF but drop the data
+    let mut discard = false;
+
+    loop {
+        if !buffer.is_empty() {
+            let to_send = buffer.split().freeze();
+
+            if !discard {
+                select! {
+                    _closed = sender.closed() => {
+                        discard = true;
+                    }
+
+                    ret = sender.send(Ok(to_send)) => {
+                        discard = ret.is_err();
+                    }
+                }
+            }
+        }
+
+        if stream.read_buf(buffer).await? == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Content-Length set on a message to be written, its values must all be
+/// the same 1*DIGIT.
+pub(crate) fn declared_length(header_map: &HeaderMap) -> Result<Option<usize>, Error> {
+    let mut length = None;
+
+    for h in header_map.get(headers::CONTENT_LENGTH).unwrap_or_default() {
+        if h.value.is_empty() || !h.value.iter().all(u8::is_ascii_digit) {
+            return Err(length_mismatch());
+        }
+        let len = String::from_utf8_lossy(&h.value)
+            .parse::<usize>()
+            .map_err(|_err| length_mismatch())?;
+
+        if length.is_some_and(|n| n != len) {
+            return Err(length_mismatch());
+        }
+        length = Some(len);
+    }
+
+    Ok(length)
+}
+
+pub(crate) fn length_mismatch() -> Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "body does not match Content-Length",
+    )
+    .into()
+}
+
+/// Write exactly `len` bytes of body, anything past them would be read by
+/// the peer as the next message.
+pub(crate) async fn write_sized_body<W>(
+    stream: &mut W,
+    body: &mut Body,
+    len: usize,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut remaining = len;
+
+    while let Some(data) = body.data().await? {
+        if data.len() > remaining {
+            stream.write_all(&data[..remaining]).await?;
+            stream.flush().await?;
+            return Err(length_mismatch());
+        }
+        remaining -= data.len();
+
+        stream.write_all(&data).await?;
+        stream.flush().await?;
+    }
+
+    if remaining > 0 {
+        return Err(length_mismatch());
+    }
+
+    Ok(())
+}
+
+/// Write a body delimited by closing the connection afterwards.
+pub(crate) async fn write_close_body<W>(stream: &mut W, body: &mut Body) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(data) = body.data().await? {
+        stream.write_all(&data).await?;
+        stream.flush().await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn write_chunked_body<W>(stream: &mut W, body: &mut Body) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(data) = body.data().await? {
+        // a zero-sized chunk would end the body
+        if data.is_empty() {
+            continue;
+        }
+
+        let size = format!("{:X}\r\n", data.len());
+        stream.write_all(size.as_bytes()).await?;
+        stream.write_all(&data).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+    }
+
+    // last-chunk and the trailer section
+    let mut trailers = body.trailers().await?;
+    // fields a recipient would reject in a trailer are never sent
+    trailers.retain(|name| {
+        !FORBIDDEN_TRAILERS
+            .iter()
+            .any(|forbidden| name.eq_ignore_ascii_case(forbidden))
+    });
+
+    let mut buf = BytesMut::with_capacity(64);
+    buf.put_slice(b"0\r\n");
+    put_header_map(&mut buf, &trailers);
+
+    stream.write_all(&buf).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::{Bytes, BytesMut};
+
+    async fn collect(mut body: Body) -> Vec<u8> {
+        let mut buf = Vec::new();
+        while let Some(d) = body.data().await.unwrap() {
+            buf.extend_from_slice(&d);
+        }
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_read_close_body() {
+        let (mut client, mut server) = tokio::io::duplex(16);
+
+        tokio::spawn(async move {
+            client.write_all(b" world, until eof").await.unwrap();
+        });
+
+        let mut buffer = BytesMut::from(&b"hello"[..]);
+        let (sender, body) = Body::channel();
+
+        let read = async move {
+            let ret = read_close_body(&mut server, &mut buffer, &sender).await;
+            drop(sender);
+            ret.map(|_| buffer)
+        };
+        let (ret, data) = tokio::join!(read, collect(body));
+
+        let buffer = ret.unwrap();
+
+        assert_eq!(data, b"hello world, until eof");
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_chunked_body() {
+        let (mut client, mut server) = tokio::io::duplex(16);
+
+        tokio::spawn(async move {
+            client
+                .write_all(b"llo\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\nnext")
+                .await
+                .unwrap();
+        });
+
+        let mut buffer = BytesMut::from(&b"5\r\nhe"[..]);
+        let (sender, mut body) = Body::channel();
+
+        let read = async move {
+            let ret = read_chunked_body(&mut server, &mut buffer, None, &sender).await;
+            drop(sender);
+            ret.map(|_| buffer)
+        };
+        let collect = async move {
+            let mut data = Vec::new();
+            while let Some(d) = body.data().await.unwrap() {
+                data.extend_from_slice(&d);
+            }
+            (data, body.trailers().await.unwrap())
+        };
+        let (ret, (data, trailers)) = tokio::join!(read, collect);
+
+        let buffer = ret.unwrap();
+
+        assert_eq!(data, b"hello world");
+        assert_eq!(trailers.get(b"expires").unwrap()[0].value, "never");
+        assert!(b"next".starts_with(&buffer[..]));
+    }
+
+    #[tokio::test]
+    async fn test_write_chunked_trailers() {
+        let (sender, mut body) = Body::channel();
+
+        tokio::spawn(async move {
+            sender.send(Ok(Bytes::from("hello"))).await.unwrap();
+            let mut trailers = HeaderMap::new();
+            trailers.append(b"Expires", b"never");
+            trailers.append(b"content-length", b"5");
+            trailers.append(b"Set-Cookie", b"a=b");
+            sender.send_trailers(trailers).await.unwrap();
+        });
+
+        let mut buf = Vec::new();
+        write_chunked_body(&mut buf, &mut body).await.unwrap();
+
+        assert_eq!(buf, b"5\r\nhello\r\n0\r\nExpires: never\r\n\r\n");
+    }
+}
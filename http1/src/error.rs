@@ -1,6 +1,6 @@
 use std::fmt;
 
-use crate::parser;
+use crate::{parser, websocket};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ErrorKind {
@@ -20,6 +20,28 @@ impl Error {
             cause: Box::new(cause),
         }
     }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        self.cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut)
+    }
+
+    /// The parse error behind a protocol error.
+    pub fn parse_error(&self) -> Option<parser::ParseError> {
+        self.cause.downcast_ref::<parser::ParseError>().copied()
+    }
+
+    /// The WebSocket protocol error behind a protocol error.
+    pub fn websocket_error(&self) -> Option<websocket::ProtocolError> {
+        self.cause
+            .downcast_ref::<websocket::ProtocolError>()
+            .copied()
+    }
 }
 
 impl std::error::Error for Error {}
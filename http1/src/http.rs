@@ -1,27 +1,35 @@
-use std::{collections::BTreeMap, fmt};
+use std::{borrow::Cow, collections::BTreeMap, fmt, ops::Range, sync::Arc};
 
 use bstr::{BStr, BString, ByteSlice};
 use bytes::{BufMut, Bytes, BytesMut};
 
 use crate::body::Body;
 use crate::error::Error;
-use crate::parser::{ParseError, RawHeader, RawRequest};
+use crate::parser::{is_token, ParseError, RawHeader, RawRequest, RawResponse};
+use crate::upgrade::OnUpgrade;
 
 pub mod headers {
     pub const CONTENT_LENGTH: &[u8] = b"Content-Length";
     pub const TRANSFER_ENCODING: &[u8] = b"Transfer-Encoding";
     pub const CONNECTION: &[u8] = b"Connection";
+    pub const HOST: &[u8] = b"Host";
+    pub const EXPECT: &[u8] = b"Expect";
+    pub const UPGRADE: &[u8] = b"Upgrade";
 
     pub const CHUNKED: &[u8] = b"chunked";
     pub const CLOSE: &[u8] = b"close";
+    pub const KEEP_ALIVE: &[u8] = b"keep-alive";
+    pub const CONTINUE: &[u8] = b"100-continue";
+    pub const UPGRADE_TOKEN: &[u8] = b"upgrade";
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Scheme {
     HTTP,
     HTTPS,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Method {
     GET,
     HEAD,
@@ -31,62 +39,685 @@ pub enum Method {
     CONNECT,
     OPTIONS,
     TRACE,
+    // rfc5789
+    PATCH,
+    // rfc4918
+    PROPFIND,
+    PROPPATCH,
+    MKCOL,
+    COPY,
+    MOVE,
+    LOCK,
+    UNLOCK,
     Unknown(BString),
 }
 
-#[derive(Debug)]
+impl Method {
+    /// Parse a method name, rfc9110 9.1, methods are case-sensitive and
+    /// anything other than a token is rejected.
+    pub fn from_bytes(method: &[u8]) -> Result<Self, ParseError> {
+        let method = match method {
+            b"GET" => Method::GET,
+            b"HEAD" => Method::HEAD,
+            b"POST" => Method::POST,
+            b"PUT" => Method::PUT,
+            b"DELETE" => Method::DELETE,
+            b"CONNECT" => Method::CONNECT,
+            b"OPTIONS" => Method::OPTIONS,
+            b"TRACE" => Method::TRACE,
+            b"PATCH" => Method::PATCH,
+            b"PROPFIND" => Method::PROPFIND,
+            b"PROPPATCH" => Method::PROPPATCH,
+            b"MKCOL" => Method::MKCOL,
+            b"COPY" => Method::COPY,
+            b"MOVE" => Method::MOVE,
+            b"LOCK" => Method::LOCK,
+            b"UNLOCK" => Method::UNLOCK,
+            m if is_token(m) => Method::Unknown(m.into()),
+            _ => return Err(ParseError::UnsupportMethod),
+        };
+
+        Ok(method)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Method::GET => b"GET",
+            Method::HEAD => b"HEAD",
+            Method::POST => b"POST",
+            Method::PUT => b"PUT",
+            Method::DELETE => b"DELETE",
+            Method::CONNECT => b"CONNECT",
+            Method::OPTIONS => b"OPTIONS",
+            Method::TRACE => b"TRACE",
+            Method::PATCH => b"PATCH",
+            Method::PROPFIND => b"PROPFIND",
+            Method::PROPPATCH => b"PROPPATCH",
+            Method::MKCOL => b"MKCOL",
+            Method::COPY => b"COPY",
+            Method::MOVE => b"MOVE",
+            Method::LOCK => b"LOCK",
+            Method::UNLOCK => b"UNLOCK",
+            Method::Unknown(m) => m.as_bytes(),
+        }
+    }
+
+    /// rfc9110 9.2.1, the request is read-only on the server. Extension
+    /// methods are never assumed to be safe.
+    pub fn is_safe(&self) -> bool {
+        matches!(
+            self,
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PROPFIND
+        )
+    }
+
+    /// rfc9110 9.2.2, repeating the request has the same effect as sending
+    /// it once, so it may be retried.
+    pub fn is_idempotent(&self) -> bool {
+        self.is_safe()
+            || matches!(
+                self,
+                Method::PUT
+                    | Method::DELETE
+                    | Method::PROPPATCH
+                    | Method::MKCOL
+                    | Method::COPY
+                    | Method::MOVE
+                    | Method::UNLOCK
+            )
+    }
+}
+
+/// A request-target, rfc9112 3.2, the components are kept as ranges
+/// into the raw bytes.
+#[derive(Debug, Clone)]
 pub struct Uri {
     raw: BString,
+    scheme: Option<Scheme>,
+    authority: Option<Range<usize>>,
+    host: Option<Range<usize>>,
+    port: Option<u16>,
+    path: Range<usize>,
+    query: Option<Range<usize>>,
 }
 
-#[derive(Debug)]
+impl Uri {
+    /// Parse a request-target in origin-form, absolute-form or asterisk-form.
+    pub fn parse(raw: &[u8]) -> Result<Self, ParseError> {
+        let mut uri = Uri {
+            raw: raw.into(),
+            scheme: None,
+            authority: None,
+            host: None,
+            port: None,
+            path: 0..0,
+            query: None,
+        };
+
+        match raw {
+            [] => return Err(ParseError::BadUri),
+            b"*" => {
+                uri.path = 0..1;
+                return Ok(uri);
+            }
+            [b'/', ..] => {}
+            _ => {
+                let colon = raw
+                    .iter()
+                    .position(|b| *b == b':')
+                    .ok_or(ParseError::BadUri)?;
+                let scheme = &raw[..colon];
+                uri.scheme = if scheme.eq_ignore_ascii_case(b"http") {
+                    Some(Scheme::HTTP)
+                } else if scheme.eq_ignore_ascii_case(b"https") {
+                    Some(Scheme::HTTPS)
+                } else {
+                    return Err(ParseError::BadUri);
+                };
+
+                if !raw[colon + 1..].starts_with(b"//") {
+                    return Err(ParseError::BadUri);
+                }
+
+                let start = colon + 3;
+                let end = raw[start..]
+                    .iter()
+                    .position(|b| matches!(b, b'/' | b'?'))
+                    .map_or(raw.len(), |p| start + p);
+
+                let (host, port) = parse_authority(raw, start..end)?;
+                uri.authority = Some(start..end);
+                uri.host = Some(host);
+                uri.port = port;
+            }
+        }
+
+        let start = uri.authority.as_ref().map_or(0, |a| a.end);
+        let (path, query) = parse_path_query(raw, start)?;
+        uri.path = path;
+        uri.query = query;
+
+        Ok(uri)
+    }
+
+    /// Parse a request-target in authority-form, which is only used by CONNECT.
+    pub fn parse_authority(raw: &[u8]) -> Result<Self, ParseError> {
+        let (host, port) = parse_authority(raw, 0..raw.len())?;
+        if port.is_none() {
+            return Err(ParseError::BadUri);
+        }
+
+        Ok(Uri {
+            raw: raw.into(),
+            scheme: None,
+            authority: Some(0..raw.len()),
+            host: Some(host),
+            port,
+            path: raw.len()..raw.len(),
+            query: None,
+        })
+    }
+
+    pub fn as_bstr(&self) -> &BStr {
+        self.raw.as_bstr()
+    }
+
+    pub fn scheme(&self) -> Option<Scheme> {
+        self.scheme
+    }
+
+    pub fn authority(&self) -> Option<&BStr> {
+        self.authority.clone().map(|r| self.raw[r].as_bstr())
+    }
+
+    /// Host of the authority, an IPv6 literal keeps its brackets.
+    pub fn host(&self) -> Option<&BStr> {
+        self.host.clone().map(|r| self.raw[r].as_bstr())
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Path of the target, empty for authority-form and `*` for asterisk-form.
+    pub fn path(&self) -> &BStr {
+        self.raw[self.path.clone()].as_bstr()
+    }
+
+    pub fn query(&self) -> Option<&BStr> {
+        self.query.clone().map(|r| self.raw[r].as_bstr())
+    }
+
+    /// Iterate `key=value` pairs of the query, decoded as
+    /// `application/x-www-form-urlencoded`, borrowed when nothing needs decoding.
+    pub fn query_pairs(&self) -> QueryPairs<'_> {
+        QueryPairs {
+            rest: self.query().map(|q| q.as_bytes()).unwrap_or_default(),
+        }
+    }
+
+    /// Iterate percent-decoded path segments, a segment containing an
+    /// encoded `/` or NUL is an error.
+    pub fn path_segments(&self) -> PathSegments<'_> {
+        let path = self.path().as_bytes();
+
+        PathSegments {
+            rest: path.strip_prefix(b"/"),
+        }
+    }
+
+    pub(crate) fn is_asterisk(&self) -> bool {
+        self.raw == "*"
+    }
+}
+
+pub struct QueryPairs<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for QueryPairs<'a> {
+    type Item = (Cow<'a, BStr>, Cow<'a, BStr>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.rest.is_empty() {
+                return None;
+            }
+
+            let end = self
+                .rest
+                .iter()
+                .position(|b| *b == b'&')
+                .unwrap_or(self.rest.len());
+            let pair = &self.rest[..end];
+            self.rest = self.rest.get(end + 1..).unwrap_or_default();
+
+            if pair.is_empty() {
+                continue;
+            }
+
+            let (key, value) = match pair.iter().position(|b| *b == b'=') {
+                Some(p) => (&pair[..p], &pair[p + 1..]),
+                None => (pair, &b""[..]),
+            };
+
+            return Some((percent_decode(key, true), percent_decode(value, true)));
+        }
+    }
+}
+
+pub struct PathSegments<'a> {
+    rest: Option<&'a [u8]>,
+}
+
+impl<'a> Iterator for PathSegments<'a> {
+    type Item = Result<Cow<'a, BStr>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest?;
+
+        let segment = match rest.iter().position(|b| *b == b'/') {
+            Some(p) => {
+                self.rest = Some(&rest[p + 1..]);
+                &rest[..p]
+            }
+            None => {
+                self.rest = None;
+                rest
+            }
+        };
+
+        let decoded = percent_decode(segment, false);
+        if decoded.contains(&b'/') || decoded.contains(&0) {
+            return Some(Err(ParseError::BadUri));
+        }
+
+        Some(Ok(decoded))
+    }
+}
+
+/// Decode `%XX` triplets, and `+` as space for form data. An invalid
+/// triplet is kept as it is.
+fn percent_decode(s: &[u8], plus_as_space: bool) -> Cow<'_, BStr> {
+    if !s
+        .iter()
+        .any(|b| *b == b'%' || (plus_as_space && *b == b'+'))
+    {
+        return Cow::Borrowed(s.as_bstr());
+    }
+
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'%' => match (
+                s.get(i + 1).and_then(|h| hex_value(*h)),
+                s.get(i + 2).and_then(|l| hex_value(*l)),
+            ) {
+                (Some(h), Some(l)) => {
+                    decoded.push(h << 4 | l);
+                    i += 3;
+                    continue;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+
+    Cow::Owned(decoded.into())
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// rfc3986 3.2, userinfo is rejected as rfc9110 4.2.4 asks, and an empty
+/// host is not allowed for http.
+fn parse_authority(
+    raw: &[u8],
+    range: Range<usize>,
+) -> Result<(Range<usize>, Option<u16>), ParseError> {
+    let authority = &raw[range.clone()];
+
+    let host_len = if authority.starts_with(b"[") {
+        let close = authority
+            .iter()
+            .position(|b| *b == b']')
+            .ok_or(ParseError::BadUri)?;
+        let literal = &authority[1..close];
+        if literal.is_empty()
+            || !literal
+                .iter()
+                .all(|b| is_unreserved(*b) || is_sub_delim(*b) || *b == b':')
+        {
+            return Err(ParseError::BadUri);
+        }
+        close + 1
+    } else {
+        let len = authority
+            .iter()
+            .position(|b| *b == b':')
+            .unwrap_or(authority.len());
+        check_chars(&authority[..len], |b| is_unreserved(b) || is_sub_delim(b))?;
+        len
+    };
+
+    if host_len == 0 {
+        return Err(ParseError::BadUri);
+    }
+
+    let port = match &authority[host_len..] {
+        [] | [b':'] => None,
+        [b':', digits @ ..] if digits.iter().all(|d| d.is_ascii_digit()) => {
+            let port = std::str::from_utf8(digits)
+                .ok()
+                .and_then(|p| p.parse::<u16>().ok())
+                .ok_or(ParseError::BadUri)?;
+            Some(port)
+        }
+        _ => return Err(ParseError::BadUri),
+    };
+
+    Ok((range.start..range.start + host_len, port))
+}
+
+/// Split `path [ "?" query ]` starting at `start`, a fragment is never part
+/// of a request-target.
+fn parse_path_query(
+    raw: &[u8],
+    start: usize,
+) -> Result<(Range<usize>, Option<Range<usize>>), ParseError> {
+    let end = raw[start..]
+        .iter()
+        .position(|b| *b == b'?')
+        .map_or(raw.len(), |p| start + p);
+
+    check_chars(&raw[start..end], |b| is_pchar(b) || b == b'/')?;
+
+    if end == raw.len() {
+        return Ok((start..end, None));
+    }
+
+    check_chars(&raw[end + 1..], |b| is_pchar(b) || b == b'/' || b == b'?')?;
+
+    Ok((start..end, Some(end + 1..raw.len())))
+}
+
+/// Every byte must be allowed or part of a pct-encoded triplet.
+fn check_chars(s: &[u8], allowed: impl Fn(u8) -> bool) -> Result<(), ParseError> {
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] == b'%' {
+            match s.get(i + 1..i + 3) {
+                Some([h, l]) if h.is_ascii_hexdigit() && l.is_ascii_hexdigit() => i += 3,
+                _ => return Err(ParseError::BadUri),
+            }
+        } else if allowed(s[i]) {
+            i += 1;
+        } else {
+            return Err(ParseError::BadUri);
+        }
+    }
+
+    Ok(())
+}
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+fn is_sub_delim(b: u8) -> bool {
+    matches!(
+        b,
+        b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'='
+    )
+}
+
+fn is_pchar(b: u8) -> bool {
+    is_unreserved(b) || is_sub_delim(b) || matches!(b, b':' | b'@')
+}
+
+/// Status code of a response, rfc9110 15, any three digit code is valid
+/// but only registered codes have a canonical reason phrase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatusCode(u16);
+
+macro_rules! status_codes {
+    ($(($code:expr, $name:ident, $reason:expr);)+) => {
+        impl StatusCode {
+            $(
+                pub const $name: StatusCode = StatusCode($code);
+            )+
+
+            /// Reason phrase registered at IANA for this code.
+            pub fn canonical_reason(&self) -> Option<&'static str> {
+                match self.0 {
+                    $($code => Some($reason),)+
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    (100, CONTINUE, "Continue");
+    (101, SWITCHING_PROTOCOLS, "Switching Protocols");
+    (102, PROCESSING, "Processing");
+    (103, EARLY_HINTS, "Early Hints");
+    (200, OK, "OK");
+    (201, CREATED, "Created");
+    (202, ACCEPTED, "Accepted");
+    (203, NON_AUTHORITATIVE_INFORMATION, "Non-Authoritative Information");
+    (204, NO_CONTENT, "No Content");
+    (205, RESET_CONTENT, "Reset Content");
+    (206, PARTIAL_CONTENT, "Partial Content");
+    (207, MULTI_STATUS, "Multi-Status");
+    (208, ALREADY_REPORTED, "Already Reported");
+    (226, IM_USED, "IM Used");
+    (300, MULTIPLE_CHOICES, "Multiple Choices");
+    (301, MOVED_PERMANENTLY, "Moved Permanently");
+    (302, FOUND, "Found");
+    (303, SEE_OTHER, "See Other");
+    (304, NOT_MODIFIED, "Not Modified");
+    (305, USE_PROXY, "Use Proxy");
+    (307, TEMPORARY_REDIRECT, "Temporary Redirect");
+    (308, PERMANENT_REDIRECT, "Permanent Redirect");
+    (400, BAD_REQUEST, "Bad Request");
+    (401, UNAUTHORIZED, "Unauthorized");
+    (402, PAYMENT_REQUIRED, "Payment Required");
+    (403, FORBIDDEN, "Forbidden");
+    (404, NOT_FOUND, "Not Found");
+    (405, METHOD_NOT_ALLOWED, "Method Not Allowed");
+    (406, NOT_ACCEPTABLE, "Not Acceptable");
+    (407, PROXY_AUTHENTICATION_REQUIRED, "Proxy Authentication Required");
+    (408, REQUEST_TIMEOUT, "Request Timeout");
+    (409, CONFLICT, "Conflict");
+    (410, GONE, "Gone");
+    (411, LENGTH_REQUIRED, "Length Required");
+    (412, PRECONDITION_FAILED, "Precondition Failed");
+    (413, CONTENT_TOO_LARGE, "Content Too Large");
+    (414, URI_TOO_LONG, "URI Too Long");
+    (415, UNSUPPORTED_MEDIA_TYPE, "Unsupported Media Type");
+    (416, RANGE_NOT_SATISFIABLE, "Range Not Satisfiable");
+    (417, EXPECTATION_FAILED, "Expectation Failed");
+    (421, MISDIRECTED_REQUEST, "Misdirected Request");
+    (422, UNPROCESSABLE_CONTENT, "Unprocessable Content");
+    (423, LOCKED, "Locked");
+    (424, FAILED_DEPENDENCY, "Failed Dependency");
+    (425, TOO_EARLY, "Too Early");
+    (426, UPGRADE_REQUIRED, "Upgrade Required");
+    (428, PRECONDITION_REQUIRED, "Precondition Required");
+    (429, TOO_MANY_REQUESTS, "Too Many Requests");
+    (431, REQUEST_HEADER_FIELDS_TOO_LARGE, "Request Header Fields Too Large");
+    (451, UNAVAILABLE_FOR_LEGAL_REASONS, "Unavailable For Legal Reasons");
+    (500, INTERNAL_SERVER_ERROR, "Internal Server Error");
+    (501, NOT_IMPLEMENTED, "Not Implemented");
+    (502, BAD_GATEWAY, "Bad Gateway");
+    (503, SERVICE_UNAVAILABLE, "Service Unavailable");
+    (504, GATEWAY_TIMEOUT, "Gateway Timeout");
+    (505, HTTP_VERSION_NOT_SUPPORTED, "HTTP Version Not Supported");
+    (506, VARIANT_ALSO_NEGOTIATES, "Variant Also Negotiates");
+    (507, INSUFFICIENT_STORAGE, "Insufficient Storage");
+    (508, LOOP_DETECTED, "Loop Detected");
+    (510, NOT_EXTENDED, "Not Extended");
+    (511, NETWORK_AUTHENTICATION_REQUIRED, "Network Authentication Required");
+}
+
+impl StatusCode {
+    pub fn from_u16(code: u16) -> Result<Self, ParseError> {
+        if !(100..1000).contains(&code) {
+            return Err(ParseError::BadStatusCode);
+        }
+
+        Ok(StatusCode(code))
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.0)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.0)
+    }
+
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.0)
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.0)
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.0)
+    }
+}
+
+impl Default for StatusCode {
+    fn default() -> Self {
+        StatusCode::OK
+    }
+}
+
+impl TryFrom<u16> for StatusCode {
+    type Error = ParseError;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        StatusCode::from_u16(code)
+    }
+}
+
+impl PartialEq<u16> for StatusCode {
+    fn eq(&self, other: &u16) -> bool {
+        self.0 == *other
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Version {
     V1_0,
     V1_1,
     V2,
 }
 
+impl Version {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            Version::V1_0 => b"HTTP/1.0",
+            Version::V1_1 => b"HTTP/1.1",
+            Version::V2 => b"HTTP/2",
+        }
+    }
+}
+
 /// HeaderMap, use titiled case name as key, a vec of header to
 /// hold raw header name.
+#[derive(Clone)]
 pub struct HeaderMap(BTreeMap<BString, Vec<Header>>);
 
+impl Default for HeaderMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HeaderMap {
     pub fn new() -> Self {
         HeaderMap(BTreeMap::new())
     }
 
-    pub fn get(&self, name: &[u8]) -> Option<&[Header]> {
+    /// Key of the entry for `name`, rfc9110 5.1 field names are
+    /// case-insensitive, even past the first letter of each word.
+    fn key(&self, name: &[u8]) -> BString {
         let key = title_case(name);
+        if self.0.contains_key(&key) {
+            return key;
+        }
+
+        self.0
+            .keys()
+            .find(|k| k.eq_ignore_ascii_case(&key))
+            .cloned()
+            .unwrap_or(key)
+    }
+
+    pub fn get(&self, name: &[u8]) -> Option<&[Header]> {
+        let key = self.key(name);
 
         self.0.get(&key).map(|v| v.as_ref())
     }
 
     pub fn set(&mut self, name: &[u8], value: &[u8]) {
-        let key = title_case(name);
+        let key = self.key(name);
         let header = Header::new(name, value);
 
         self.0.insert(key, vec![header]);
     }
 
     pub fn append(&mut self, name: &[u8], value: &[u8]) {
-        let key = title_case(name);
+        let key = self.key(name);
         let header = Header::new(name, value);
 
-        self.0.entry(key).or_insert_with(|| Vec::new()).push(header);
+        self.0.entry(key).or_default().push(header);
     }
 
     pub fn remove(&mut self, name: &BStr) {
-        let key = title_case(name);
+        let key = self.key(name);
 
         self.0.remove(&key);
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&[u8]) -> bool) {
+        self.0.retain(|name, _| keep(name));
+    }
 }
 
 impl fmt::Debug for HeaderMap {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_map()
-            .entries(self.0.iter().map(|(ref k, ref v)| (*k, *v)))
-            .finish()
+        f.debug_map().entries(self.0.iter()).finish()
     }
 }
 
@@ -126,14 +757,30 @@ pub enum ContentLength {
 pub(crate) struct RequestInfo {
     pub content_length: ContentLength,
     pub should_close: bool,
+    pub expect_continue: bool,
+    pub upgrade: bool,
 }
 
 impl RequestInfo {
     pub fn new() -> Self {
-        RequestInfo { content_length: ContentLength::None, should_close: false }
+        RequestInfo {
+            content_length: ContentLength::None,
+            should_close: false,
+            expect_continue: false,
+            upgrade: false,
+        }
     }
 }
 
+/// What the TLS handshake of a connection negotiated.
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    /// Server name the client asked for with SNI.
+    pub server_name: Option<String>,
+    /// Protocol agreed on with ALPN.
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
 #[derive(Debug)]
 pub struct Request {
     pub method: Method,
@@ -141,113 +788,293 @@ pub struct Request {
     pub version: Version,
     pub header_map: HeaderMap,
     pub body: Body,
+    pub(crate) upgrade: Option<OnUpgrade>,
+    pub(crate) tls: Option<Arc<TlsInfo>>,
 }
 
 impl Request {
-    // pub fn new(url: &str) -> Self {
-    //     Request { method: Method::GET, uri: (), version: (), header_map: (), content_length: () }
-    // }
+    /// A copy of the request to send again, when its body can be.
+    pub(crate) fn try_clone(&self) -> Option<Self> {
+        Some(Request {
+            method: self.method.clone(),
+            uri: self.uri.clone(),
+            version: self.version,
+            header_map: self.header_map.clone(),
+            body: self.body.try_clone()?,
+            upgrade: None,
+            tls: self.tls.clone(),
+        })
+    }
 
-   pub(crate) fn from_raw_request(req: RawRequest<'_>, info: &mut RequestInfo) -> Result<Self, Error> {
-        let method = match req.method {
-            b"GET" => Method::GET,
-            b"HEAD" => Method::HEAD,
-            b"POST" => Method::POST,
-            b"PUT" => Method::PUT,
-            b"DELETE" => Method::DELETE,
-            b"CONNECT" => Method::CONNECT,
-            b"OPTIONS" => Method::OPTIONS,
-            b"TRACE" => Method::TRACE,
-            _ => Method::Unknown(req.method.into()),
-        };
+    pub fn new(method: Method, uri: Uri) -> Self {
+        Request {
+            method,
+            uri,
+            version: Version::V1_1,
+            header_map: HeaderMap::new(),
+            body: Body::empty(),
+            upgrade: None,
+            tls: None,
+        }
+    }
+
+    /// Scheme of the connection the request was received on.
+    pub fn scheme(&self) -> Scheme {
+        match self.tls {
+            Some(_) => Scheme::HTTPS,
+            None => Scheme::HTTP,
+        }
+    }
+
+    /// TLS parameters of the connection, None for a plain one.
+    pub fn tls_info(&self) -> Option<&TlsInfo> {
+        self.tls.as_deref()
+    }
+
+    pub fn header_buf(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(1024);
+
+        buf.put_slice(self.method.as_bytes());
+        buf.put_slice(b" ");
+        buf.put_slice(self.uri.as_bstr());
+        buf.put_slice(b" ");
+        buf.put_slice(self.version.as_bytes());
+        buf.put_slice(b"\r\n");
+
+        put_header_map(&mut buf, &self.header_map);
 
-        let uri = Uri {
-            raw: req.uri.into(),
+        buf.freeze()
+    }
+
+    pub(crate) fn from_raw_request(
+        req: RawRequest<'_>,
+        info: &mut RequestInfo,
+    ) -> Result<Self, Error> {
+        let method = Method::from_bytes(req.method)?;
+
+        let uri = match method {
+            Method::CONNECT => Uri::parse_authority(req.uri)?,
+            _ => Uri::parse(req.uri)?,
         };
+        if uri.is_asterisk() && !matches!(method, Method::OPTIONS) {
+            return Err(ParseError::BadUri.into());
+        }
+
+        // rfc9110 6.2, a higher minor version is served as the highest one
+        // known, only major version 1 is spoken here
         let version = match req.version {
             b"1.0" => Version::V1_0,
-            b"1.1" => Version::V1_1,
-            _ => Version::V1_1,
+            [b'1', b'.', _] => Version::V1_1,
+            _ => return Err(ParseError::UnsupportVersion.into()),
         };
 
-        let mut should_close = false;
-        let mut content_length = ContentLength::None;
-        let mut header_map = HeaderMap::new();
+        // rfc9112 6.3, a request body is never delimited by closing the connection
+        info.content_length = request_framing(&req.headers, version)?;
 
-        let mut had_transfer_encoding = false;
-        for h in req.headers {
+        let mut header_map = HeaderMap::new();
+        let mut connection_upgrade = false;
+        let mut keep_alive = false;
+        let mut upgrade = false;
+        for h in &req.headers {
             header_map.append(h.name, h.value);
 
-            if h.name.eq_ignore_ascii_case(headers::TRANSFER_ENCODING) {
-                had_transfer_encoding = true;
-                if header_values_contains_token(h.value, headers::CHUNKED) {
-                    content_length = ContentLength::Chunked;
+            if h.name.eq_ignore_ascii_case(headers::CONNECTION) {
+                if header_values_contains_token(h.value, headers::CLOSE) {
+                    info.should_close = true;
                 }
-            } else if h.name.eq_ignore_ascii_case(headers::CONTENT_LENGTH) {
-                if had_transfer_encoding {
-                    return Err(ParseError::BadRequest.into());
+                if header_values_contains_token(h.value, headers::UPGRADE_TOKEN) {
+                    connection_upgrade = true;
                 }
-
-                // content-length must be digit
-                for d in h.value {
-                    if !d.is_ascii_digit() {
-                        return Err(ParseError::BadRequest.into());
-                    }
-                }
-                match String::from_utf8_lossy(h.value).parse::<usize>() {
-                    Ok(len) => {
-                        info.content_length = ContentLength::Sized(len);
-                    }
-                    Err(_err) => return Err(ParseError::BadRequest.into()),
+                if header_values_contains_token(h.value, headers::KEEP_ALIVE) {
+                    keep_alive = true;
                 }
-            } else if h.name.eq_ignore_ascii_case(headers::CONNECTION) {
-                if header_values_contains_token(h.value, headers::CLOSE) {
-                    info.should_close = true;
-                    info.content_length = ContentLength::Close;
+            } else if h.name.eq_ignore_ascii_case(headers::UPGRADE) {
+                upgrade = !h.value.is_empty();
+            } else if h.name.eq_ignore_ascii_case(headers::EXPECT) && version == Version::V1_1 {
+                // rfc9110 10.1.1, 100-continue is the only expectation
+                // defined, and it is ignored in HTTP/1.0 requests
+                if !h.value.trim().eq_ignore_ascii_case(headers::CONTINUE) {
+                    return Err(ParseError::UnsupportExpectation.into());
                 }
+                info.expect_continue = true;
             }
         }
 
+        // rfc9112 9.3, HTTP/1.0 connections are only kept open on request
+        if version == Version::V1_0 && !keep_alive {
+            info.should_close = true;
+        }
+
+        // rfc9110 7.8, an upgrade is only offered along with `Connection: upgrade`
+        info.upgrade = connection_upgrade && upgrade && matches!(version, Version::V1_1);
+
         Ok(Request {
             method,
             uri,
             version,
             header_map,
             body: Body::empty(),
+            upgrade: None,
+            tls: None,
         })
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct ResponseInfo {
+    pub content_length: ContentLength,
+    pub should_close: bool,
+}
+
+impl ResponseInfo {
+    pub fn new() -> Self {
+        ResponseInfo {
+            content_length: ContentLength::None,
+            should_close: false,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Response {
-    pub status_code: u16,
+    pub status_code: StatusCode,
     pub header_map: HeaderMap,
+    pub body: Body,
+    reason: Option<BString>,
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Response {
     pub fn new() -> Self {
         Response {
-            status_code: 200,
+            status_code: StatusCode::OK,
             header_map: HeaderMap::new(),
+            body: Body::empty(),
+            reason: None,
         }
     }
 
-    pub fn header_buf(self) -> Bytes {
-        let mut buf = BytesMut::with_capacity(1024);
+    pub fn with_status(status_code: StatusCode) -> Self {
+        Response {
+            status_code,
+            ..Response::new()
+        }
+    }
 
-        self.put_status_line(&mut buf);
+    /// Reason phrase sent in the status line, the canonical one unless overridden.
+    pub fn reason(&self) -> &BStr {
+        match &self.reason {
+            Some(reason) => reason.as_bstr(),
+            None => self.status_code.canonical_reason().unwrap_or("").into(),
+        }
+    }
+
+    /// Override the reason phrase, rfc9112 4 only allows HTAB, SP, VCHAR and obs-text.
+    pub fn set_reason(&mut self, reason: &[u8]) -> Result<(), ParseError> {
+        if reason
+            .iter()
+            .any(|b| !matches!(b, b'\t' | b' '..=b'~' | 0x80..=0xff))
+        {
+            return Err(ParseError::BadResponse);
+        }
+
+        self.reason = Some(reason.into());
+        Ok(())
+    }
+
+    /// Build a response from a parsed status line and header block, `method`
+    /// is the method of the request this response answers.
+    pub(crate) fn from_raw_response(
+        rsp: RawResponse<'_>,
+        method: &Method,
+        info: &mut ResponseInfo,
+    ) -> Result<Self, Error> {
+        let status_code = StatusCode::from_u16(
+            rsp.status_code
+                .iter()
+                .fold(0u16, |n, d| n * 10 + (d - b'0') as u16),
+        )
+        .map_err(|_| ParseError::BadResponse)?;
+
+        let mut header_map = HeaderMap::new();
+
+        let mut content_length = None;
+        let mut transfer_encoding = None;
+        for h in rsp.headers {
+            header_map.append(h.name, h.value);
 
-        for (_, values) in self.header_map.0 {
-            for v in values {
-                buf.put_slice(&v.name);
-                buf.put_slice(b": ");
-                buf.put_slice(&v.value);
-                buf.put_slice(b"\r\n");
+            if h.name.eq_ignore_ascii_case(headers::TRANSFER_ENCODING) {
+                transfer_encoding = Some(header_values_last_token(h.value));
+            } else if h.name.eq_ignore_ascii_case(headers::CONTENT_LENGTH) {
+                match std::str::from_utf8(h.value).map(|v| v.parse::<usize>()) {
+                    Ok(Ok(len)) if content_length.unwrap_or(len) == len => {
+                        content_length = Some(len);
+                    }
+                    _ => return Err(ParseError::BadResponse.into()),
+                }
+            } else if h.name.eq_ignore_ascii_case(headers::CONNECTION)
+                && header_values_contains_token(h.value, headers::CLOSE)
+            {
+                info.should_close = true;
             }
         }
 
-        buf.put_slice(b"\r\n");
+        if rsp.version == b"1.0"
+            && !header_map
+                .get(headers::CONNECTION)
+                .unwrap_or_default()
+                .iter()
+                .any(|h| header_values_contains_token(&h.value, headers::KEEP_ALIVE))
+        {
+            info.should_close = true;
+        }
+
+        // rfc9112 6.3
+        info.content_length = if matches!(method, Method::HEAD)
+            || status_code.is_informational()
+            || status_code == StatusCode::NO_CONTENT
+            || status_code == StatusCode::NOT_MODIFIED
+            || (matches!(method, Method::CONNECT) && status_code.is_success())
+        {
+            ContentLength::None
+        } else {
+            match (transfer_encoding, content_length) {
+                (Some(coding), _) if coding.eq_ignore_ascii_case(headers::CHUNKED) => {
+                    ContentLength::Chunked
+                }
+                (Some(_), _) => ContentLength::Close,
+                (None, Some(len)) => ContentLength::Sized(len),
+                (None, None) => ContentLength::Close,
+            }
+        };
+
+        if matches!(info.content_length, ContentLength::Close) {
+            info.should_close = true;
+        }
+
+        // keep the reason phrase only when it is not the canonical one
+        let reason = (status_code.canonical_reason().map(str::as_bytes) != Some(rsp.reason))
+            .then(|| rsp.reason.into());
+
+        Ok(Response {
+            status_code,
+            header_map,
+            body: Body::empty(),
+            reason,
+        })
+    }
+
+    pub fn header_buf(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(1024);
+
+        self.put_status_line(&mut buf);
 
-        // println!("=> {:?}", String::from_utf8_lossy(&buf));
+        put_header_map(&mut buf, &self.header_map);
 
         buf.freeze()
     }
@@ -256,21 +1083,24 @@ impl Response {
         buf.put_slice(b"HTTP/1.1 ");
         buf.put_slice(self.status_code.to_string().as_bytes());
         buf.put_slice(b" ");
-
-        let s: &'static [u8] = match self.status_code {
-            200 => b"OK",
-            400 => b"Bad Request",
-            404 => b"Not Found",
-            500 => b"Internal Server Error",
-            _ => unimplemented!(),
-        };
-
-        buf.put_slice(s);
-
+        buf.put_slice(self.reason());
         buf.put_slice(b"\r\n");
     }
 }
 
+pub(crate) fn put_header_map(buf: &mut BytesMut, header_map: &HeaderMap) {
+    for values in header_map.0.values() {
+        for v in values {
+            buf.put_slice(&v.name);
+            buf.put_slice(b": ");
+            buf.put_slice(&v.value);
+            buf.put_slice(b"\r\n");
+        }
+    }
+
+    buf.put_slice(b"\r\n");
+}
+
 fn title_case(s: &[u8]) -> BString {
     let mut ret = BString::new(Vec::with_capacity(s.len()));
     let mut upper = true;
@@ -288,7 +1118,79 @@ fn title_case(s: &[u8]) -> BString {
     ret
 }
 
-fn header_values_contains_token(values: &[u8], token: &[u8]) -> bool {
+/// Framing of a request body, rfc9112 6.1 and 6.3. Anything another hop
+/// could read differently is rejected rather than repaired, a request
+/// smuggled past a proxy in front would otherwise be served.
+fn request_framing(
+    raw_headers: &[RawHeader<'_>],
+    version: Version,
+) -> Result<ContentLength, ParseError> {
+    let mut content_length = None;
+    let mut codings = Vec::new();
+    let mut transfer_encoding = false;
+
+    for h in raw_headers {
+        if h.name.eq_ignore_ascii_case(headers::TRANSFER_ENCODING) {
+            transfer_encoding = true;
+
+            // only OWS is trimmed, a coding padded with anything else is unknown
+            codings.extend(
+                h.value
+                    .split_str(",")
+                    .map(|coding| coding.trim_with(|c| matches!(c, ' ' | '\t')))
+                    .filter(|coding| !coding.is_empty()),
+            );
+        } else if h.name.eq_ignore_ascii_case(headers::CONTENT_LENGTH) {
+            // Content-Length = 1*DIGIT
+            if h.value.is_empty() || !h.value.iter().all(u8::is_ascii_digit) {
+                return Err(ParseError::BadRequest);
+            }
+            // all digits, so it is only too large to be handled
+            let len = String::from_utf8_lossy(h.value)
+                .parse::<usize>()
+                .map_err(|_err| ParseError::ContentTooLarge)?;
+
+            // 6.3.5, duplicates must agree on the length
+            if content_length.is_some_and(|n| n != len) {
+                return Err(ParseError::BadRequest);
+            }
+            content_length = Some(len);
+        }
+    }
+
+    if !transfer_encoding {
+        return Ok(content_length.map_or(ContentLength::None, ContentLength::Sized));
+    }
+
+    // 6.3.3, both present is how one request is read as two, and HTTP/1.0
+    // does not know transfer-codings at all
+    if content_length.is_some() || version == Version::V1_0 {
+        return Err(ParseError::BadRequest);
+    }
+
+    // 6.1, chunked is applied exactly once, as the final coding, and no other
+    // coding is implemented to decode the body underneath it
+    let chunked = codings
+        .iter()
+        .filter(|coding| coding.eq_ignore_ascii_case(headers::CHUNKED))
+        .count();
+    match codings.last() {
+        Some(last) if chunked == 1 && last.eq_ignore_ascii_case(headers::CHUNKED) => {
+            match codings.len() {
+                1 => Ok(ContentLength::Chunked),
+                _ => Err(ParseError::UnsupportTransferCoding),
+            }
+        }
+        Some(_) if chunked == 0 => Err(ParseError::UnsupportTransferCoding),
+        _ => Err(ParseError::BadRequest),
+    }
+}
+
+pub(crate) fn header_values_last_token(values: &[u8]) -> &[u8] {
+    values.rsplit_str(",").next().unwrap_or_default().trim()
+}
+
+pub(crate) fn header_values_contains_token(values: &[u8], token: &[u8]) -> bool {
     for part in values.split_str(",") {
         if part.trim().eq_ignore_ascii_case(token) {
             return true;
@@ -310,4 +1212,343 @@ mod test {
         assert_eq!("X-Forwarded-For", title_case(BStr::new("X-Forwarded-For")));
         assert_eq!("Via", title_case(BStr::new("via")));
     }
+
+    #[test]
+    fn test_header_map() {
+        let mut map = HeaderMap::new();
+        map.append(b"Sec-WebSocket-Key", b"a");
+        map.append(b"sec-websocket-key", b"b");
+
+        let values = map.get(b"SEC-WEBSOCKET-KEY").unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0].name, "Sec-WebSocket-Key");
+        assert_eq!(values[1].name, "sec-websocket-key");
+
+        map.set(b"Sec-Websocket-Key", b"c");
+        assert_eq!(map.get(b"sec-websocket-key").unwrap().len(), 1);
+
+        map.remove(BStr::new("SEC-websocket-KEY"));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_method() {
+        assert_eq!(Method::from_bytes(b"GET"), Ok(Method::GET));
+        assert_eq!(Method::from_bytes(b"PATCH"), Ok(Method::PATCH));
+        assert_eq!(Method::from_bytes(b"PROPFIND"), Ok(Method::PROPFIND));
+        assert_eq!(
+            Method::from_bytes(b"get"),
+            Ok(Method::Unknown(BString::from("get")))
+        );
+        assert_eq!(
+            Method::from_bytes(b"M-SEARCH").unwrap().as_bytes(),
+            b"M-SEARCH"
+        );
+
+        assert_eq!(Method::from_bytes(b""), Err(ParseError::UnsupportMethod));
+        assert_eq!(Method::from_bytes(b"G@T"), Err(ParseError::UnsupportMethod));
+        assert_eq!(
+            Method::from_bytes(b"GE T"),
+            Err(ParseError::UnsupportMethod)
+        );
+
+        assert!(Method::GET.is_safe() && Method::GET.is_idempotent());
+        assert!(Method::PROPFIND.is_safe());
+        assert!(!Method::PUT.is_safe() && Method::PUT.is_idempotent());
+        assert!(Method::MOVE.is_idempotent());
+        assert!(!Method::POST.is_idempotent());
+        assert!(!Method::PATCH.is_idempotent());
+        assert!(!Method::LOCK.is_idempotent());
+        assert!(!Method::Unknown(BString::from("PURGE")).is_idempotent());
+    }
+
+    #[test]
+    fn test_uri_parse() {
+        let uri = Uri::parse(b"/where?q=now&x=%20").unwrap();
+        assert_eq!(uri.scheme(), None);
+        assert_eq!(uri.authority(), None);
+        assert_eq!(uri.path(), "/where");
+        assert_eq!(uri.query().unwrap(), "q=now&x=%20");
+
+        let uri = Uri::parse(b"HTTP://www.example.org:8080/pub/WWW/TheProject.html").unwrap();
+        assert_eq!(uri.scheme(), Some(Scheme::HTTP));
+        assert_eq!(uri.authority().unwrap(), "www.example.org:8080");
+        assert_eq!(uri.host().unwrap(), "www.example.org");
+        assert_eq!(uri.port(), Some(8080));
+        assert_eq!(uri.path(), "/pub/WWW/TheProject.html");
+        assert_eq!(uri.query(), None);
+
+        let uri = Uri::parse(b"https://[::1]?").unwrap();
+        assert_eq!(uri.scheme(), Some(Scheme::HTTPS));
+        assert_eq!(uri.host().unwrap(), "[::1]");
+        assert_eq!(uri.port(), None);
+        assert_eq!(uri.path(), "");
+        assert_eq!(uri.query().unwrap(), "");
+
+        let uri = Uri::parse(b"*").unwrap();
+        assert_eq!(uri.path(), "*");
+
+        let uri = Uri::parse_authority(b"www.example.com:443").unwrap();
+        assert_eq!(uri.host().unwrap(), "www.example.com");
+        assert_eq!(uri.port(), Some(443));
+        assert_eq!(uri.path(), "");
+
+        for bad in [
+            &b""[..],
+            b"index.html",
+            b"/a b",
+            b"/a#frag",
+            b"/%zz",
+            b"/%2",
+            b"ftp://example.com/",
+            b"http:/example.com",
+            b"http://",
+            b"http://user@example.com/",
+            b"http://example.com:65536/",
+            b"http://[::1/",
+        ] {
+            assert_eq!(
+                Uri::parse(bad).unwrap_err(),
+                ParseError::BadUri,
+                "{:?}",
+                bad
+            );
+        }
+
+        assert!(Uri::parse_authority(b"www.example.com").is_err());
+        assert!(Uri::parse_authority(b"/index.html").is_err());
+    }
+
+    #[test]
+    fn test_uri_query_pairs() {
+        let uri = Uri::parse(b"/search?q=a+b%26c&&empty=&flag&k=%E4%BD%A0").unwrap();
+        let pairs: Vec<_> = uri.query_pairs().collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (Cow::from(BStr::new("q")), Cow::from(BStr::new("a b&c"))),
+                (Cow::from(BStr::new("empty")), Cow::from(BStr::new(""))),
+                (Cow::from(BStr::new("flag")), Cow::from(BStr::new(""))),
+                (Cow::from(BStr::new("k")), Cow::from(BStr::new("你"))),
+            ]
+        );
+        assert!(matches!(pairs[1].0, Cow::Borrowed(_)));
+
+        assert_eq!(Uri::parse(b"/").unwrap().query_pairs().count(), 0);
+    }
+
+    #[test]
+    fn test_uri_path_segments() {
+        let uri = Uri::parse(b"/a/b%20c/").unwrap();
+        let segments: Vec<_> = uri
+            .path_segments()
+            .map(|s| s.unwrap().to_string())
+            .collect();
+        assert_eq!(segments, vec!["a", "b c", ""]);
+
+        // form encoding does not apply to paths
+        let uri = Uri::parse(b"/a+b").unwrap();
+        assert_eq!(*uri.path_segments().next().unwrap().unwrap(), "a+b");
+
+        for bad in [&b"/a%2Fb"[..], b"/a%2f", b"/%00"] {
+            let uri = Uri::parse(bad).unwrap();
+            assert!(uri.path_segments().any(|s| s.is_err()), "{:?}", bad);
+        }
+
+        assert_eq!(Uri::parse(b"*").unwrap().path_segments().count(), 0);
+    }
+
+    #[test]
+    fn test_request_target_form() {
+        assert!(request_info(b"OPTIONS * HTTP/1.1\r\n\r\n").is_ok());
+        assert!(request_info(b"GET * HTTP/1.1\r\n\r\n").is_err());
+        assert!(request_info(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").is_ok());
+        assert!(request_info(b"CONNECT / HTTP/1.1\r\n\r\n").is_err());
+        assert!(request_info(b"GET http://example.com/ HTTP/1.1\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn test_status_code() {
+        assert_eq!(StatusCode::from_u16(201).unwrap(), StatusCode::CREATED);
+        assert_eq!(StatusCode::CREATED.canonical_reason(), Some("Created"));
+        assert_eq!(StatusCode::from_u16(599).unwrap().canonical_reason(), None);
+        assert!(StatusCode::from_u16(99).is_err());
+        assert!(StatusCode::from_u16(1000).is_err());
+        assert!(StatusCode::SERVICE_UNAVAILABLE.is_server_error());
+        assert!(!StatusCode::NOT_FOUND.is_server_error());
+    }
+
+    #[test]
+    fn test_response_status_line() {
+        let head = |resp: &Response| {
+            let buf = resp.header_buf();
+            let end = buf.iter().position(|b| *b == b'\r').unwrap();
+            BString::from(&buf[..end])
+        };
+
+        let mut resp = Response::with_status(StatusCode::NO_CONTENT);
+        assert_eq!(head(&resp), "HTTP/1.1 204 No Content");
+
+        resp.status_code = StatusCode::from_u16(599).unwrap();
+        assert_eq!(head(&resp), "HTTP/1.1 599 ");
+
+        resp.set_reason(b"Try Later").unwrap();
+        assert_eq!(head(&resp), "HTTP/1.1 599 Try Later");
+
+        assert!(resp.set_reason(b"Bad\r\nX-Injected: 1").is_err());
+        assert_eq!(resp.reason(), "Try Later");
+    }
+
+    fn request_info(buf: &[u8]) -> Result<RequestInfo, Error> {
+        let mut req = RawRequest::new();
+        crate::parser::parse_request(buf, &mut req)?;
+
+        let mut info = RequestInfo::new();
+        Request::from_raw_request(req, &mut info).map(|_| info)
+    }
+
+    #[test]
+    fn test_request_content_length() {
+        let info = request_info(b"GET / HTTP/1.0\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(matches!(info.content_length, ContentLength::None));
+        assert!(info.should_close);
+
+        let info = request_info(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n").unwrap();
+        assert!(matches!(info.content_length, ContentLength::Sized(10)));
+        assert!(!info.should_close);
+
+        let info = request_info(b"POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n").unwrap();
+        assert!(matches!(info.content_length, ContentLength::Chunked));
+
+        // rfc9112 6.1, gzip under chunked is a coding this server can't decode
+        for buf in [
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ] {
+            let err = request_info(buf).unwrap_err();
+            assert_eq!(err.parse_error(), Some(ParseError::UnsupportTransferCoding));
+        }
+
+        let info =
+            request_info(b"POST / HTTP/1.1\r\nContent-Length: 10\r\nContent-Length: 10\r\n\r\n")
+                .unwrap();
+        assert!(matches!(info.content_length, ContentLength::Sized(10)));
+
+        assert!(request_info(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").is_err());
+        assert!(request_info(b"POST / HTTP/1.1\r\nContent-Length: 1x\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_request_smuggling() {
+        for (payload, err) in [
+            // CL.TE and TE.CL
+            (
+                &b"POST / HTTP/1.1\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n"[..],
+                ParseError::BadRequest,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 6\r\n\r\n",
+                ParseError::BadRequest,
+            ),
+            // lengths that disagree
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 6\r\nContent-Length: 5\r\n\r\n",
+                ParseError::BadRequest,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 6, 5\r\n\r\n",
+                ParseError::BadRequest,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: +6\r\n\r\n",
+                ParseError::BadRequest,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: \r\n\r\n",
+                ParseError::BadRequest,
+            ),
+            // TE.TE, chunked that is not the final coding or applied twice
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: x\r\n\r\n",
+                ParseError::BadRequest,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+                ParseError::BadRequest,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, chunked\r\n\r\n",
+                ParseError::BadRequest,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: ,\r\n\r\n",
+                ParseError::BadRequest,
+            ),
+            // obfuscated chunked is an unknown coding
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: xchunked\r\n\r\n",
+                ParseError::UnsupportTransferCoding,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: \x0bchunked\r\n\r\n",
+                ParseError::UnsupportTransferCoding,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: \"chunked\"\r\n\r\n",
+                ParseError::UnsupportTransferCoding,
+            ),
+            // HTTP/1.0 does not know transfer-codings
+            (
+                b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n",
+                ParseError::BadRequest,
+            ),
+            // whitespace before the colon, and obs-fold
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding : chunked\r\n\r\n",
+                ParseError::BadHeaderName,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nX-Foo: bar\r\n Transfer-Encoding: chunked\r\n\r\n",
+                ParseError::BadHeaderName,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding:\r\n\tchunked\r\n\r\n",
+                ParseError::BadHeaderName,
+            ),
+            (
+                b"POST / HTTP/1.1\r\n Host: example.com\r\n\r\n",
+                ParseError::BadHeaderName,
+            ),
+        ] {
+            let ret = request_info(payload).map_err(|err| err.parse_error());
+            assert_eq!(ret.err(), Some(Some(err)), "{:?}", BStr::new(payload));
+        }
+    }
+
+    #[test]
+    fn test_request_version() {
+        let info = request_info(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert!(info.should_close);
+
+        let info = request_info(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap();
+        assert!(!info.should_close);
+
+        let info = request_info(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(!info.should_close);
+
+        let info = request_info(b"POST / HTTP/1.0\r\nExpect: something\r\n\r\n").unwrap();
+        assert!(!info.expect_continue);
+        let err = request_info(b"POST / HTTP/1.1\r\nExpect: something\r\n\r\n").unwrap_err();
+        assert_eq!(err.parse_error(), Some(ParseError::UnsupportExpectation));
+
+        let mut req = RawRequest::new();
+        crate::parser::parse_request(b"GET / HTTP/1.9\r\n\r\n", &mut req).unwrap();
+        let req = Request::from_raw_request(req, &mut RequestInfo::new()).unwrap();
+        assert_eq!(req.version, Version::V1_1);
+
+        for buf in [&b"GET / HTTP/2.0\r\n\r\n"[..], b"GET / HTTP/0.9\r\n\r\n"] {
+            let err = request_info(buf).unwrap_err();
+            assert_eq!(err.parse_error(), Some(ParseError::UnsupportVersion));
+        }
+    }
 }
@@ -1,6 +1,12 @@
 pub mod body;
+pub mod client;
+mod codec;
 pub mod error;
 pub mod http;
 pub mod parser;
 pub mod parser2;
 pub mod server;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod upgrade;
+pub mod websocket;
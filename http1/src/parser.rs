@@ -24,7 +24,39 @@ const TCHAR_TABLE: [bool; 127] = [
     true, true, true, true, true, true, true, false, true, false, true,
 ];
 
+// room made up front for the usual message, most have fewer headers
 const DEFAULT_HEADER_COUNT: usize = 16;
+// more than this is refused unless the server is configured otherwise
+pub(crate) const DEFAULT_MAX_HEADERS: usize = 100;
+
+// rfc9110 6.5.1, fields for framing, routing, request modifiers,
+// authentication and content handling are not allowed in a trailer section
+pub(crate) const FORBIDDEN_TRAILERS: &[&[u8]] = &[
+    b"Content-Length",
+    b"Transfer-Encoding",
+    b"Trailer",
+    b"TE",
+    b"Connection",
+    b"Host",
+    b"Expect",
+    b"Max-Forwards",
+    b"Cache-Control",
+    b"Range",
+    b"If-Match",
+    b"If-None-Match",
+    b"If-Modified-Since",
+    b"If-Unmodified-Since",
+    b"If-Range",
+    b"Authorization",
+    b"Proxy-Authorization",
+    b"WWW-Authenticate",
+    b"Proxy-Authenticate",
+    b"Cookie",
+    b"Set-Cookie",
+    b"Content-Type",
+    b"Content-Encoding",
+    b"Content-Range",
+];
 
 pub struct RawHeader<'a> {
     pub(crate) name: &'a [u8],
@@ -124,11 +156,19 @@ pub enum ParseError {
     TooLarge,
     BadVersion,
     BadRequest,
+    BadUri,
     BadResponse,
+    BadStatusCode,
     UnsupportMethod,
+    UnsupportTransferCoding,
+    UnsupportExpectation,
+    UnsupportVersion,
+    ContentTooLarge,
+    RequestLineTooLong,
     BadData,
     BadHeaderName,
     BadHeaderValue,
+    BadTrailer,
 
 }
 
@@ -139,11 +179,19 @@ impl fmt::Display for ParseError {
             ParseError::TooLarge => write!(f, "TooLarge"),
             ParseError::BadVersion => write!(f, "BadVersion"),
             ParseError::BadRequest => write!(f, "BadRequest"),
+            ParseError::BadUri => write!(f, "BadUri"),
             ParseError::BadResponse => write!(f, "BadResponse"),
+            ParseError::BadStatusCode => write!(f, "BadStatusCode"),
             ParseError::UnsupportMethod => write!(f, "UnsupportMethod"),
+            ParseError::UnsupportTransferCoding => write!(f, "UnsupportTransferCoding"),
+            ParseError::UnsupportExpectation => write!(f, "UnsupportExpectation"),
+            ParseError::UnsupportVersion => write!(f, "UnsupportVersion"),
+            ParseError::ContentTooLarge => write!(f, "ContentTooLarge"),
+            ParseError::RequestLineTooLong => write!(f, "RequestLineTooLong"),
             ParseError::BadData => write!(f, "BadData"),
             ParseError::BadHeaderName => write!(f, "BadHeaderName"),
             ParseError::BadHeaderValue => write!(f, "BadHeaderValue"),
+            ParseError::BadTrailer => write!(f, "BadTrailer"),
         }
     }
 }
@@ -175,8 +223,11 @@ pub fn parse_request<'a>(buf: &'a [u8], req: &mut RawRequest<'a>) -> Result<usiz
 fn parse_request_line<'a>(buf: &'a [u8], req: &mut RawRequest<'a>) -> Result<(), ParseError> {
     let input = buf;
 
-    // get method until space
+    // get method until space, rfc9110 9.1 method = token
     let (input, method) = must_split(input, BYTE_SP)?;
+    if !is_token(method) {
+        return Err(ParseError::UnsupportMethod);
+    }
     req.method = method;
 
     let (input, uri) = must_split(input, BYTE_SP)?;
@@ -192,10 +243,29 @@ fn parse_headers<'a>(buf: &'a [u8], headers: &mut Vec<RawHeader<'a>>) -> Result<
     let mut input = buf;
 
     loop {
+        if input.len() < 2 {
+            return Err(ParseError::Incomplete);
+        }
+        if input[..2] == BYTES_CRLF {
+            input = &input[2..];
+            break;
+        }
+
         let (i, line) = read_line(input)?;
 
+        // rfc9112 5.2, obs-fold is rejected rather than joined to the previous field
+        if line.first().copied().is_some_and(is_whitespace) {
+            return Err(ParseError::BadHeaderName);
+        }
+
+        // the line is complete here, a missing colon is a bad header name,
+        // rfc9112 5.1 whitespace before the colon is not a tchar either
         let (value, name) =
-            validate_until(line, BYTE_COLON, |b| b < 127 && TCHAR_TABLE[b as usize])?;
+            validate_until(line, BYTE_COLON, |b| b < 127 && TCHAR_TABLE[b as usize])
+                .map_err(|_| ParseError::BadHeaderName)?;
+        if name.is_empty() {
+            return Err(ParseError::BadHeaderName);
+        }
 
         // validate header value, reject bad data
         // a recipient of CR, LF, or NUL within a field value 
@@ -209,14 +279,6 @@ fn parse_headers<'a>(buf: &'a [u8], headers: &mut Vec<RawHeader<'a>>) -> Result<
 
         headers.push(RawHeader::new(name, value));
 
-        if i.len() < 2 {
-            return Err(ParseError::Incomplete);
-        }
-        if &i[..2] == b"\r\n" {
-            input = &i[2..];
-            break;
-        }
-
         input = i;
     }
 
@@ -258,27 +320,69 @@ fn parse_status_line<'a>(buf: &'a [u8], rsp: &mut RawResponse<'a>) -> Result<(),
     Ok(())
 }
 
-fn parse_http_version(input: &[u8]) -> Result<&[u8], ParseError> {
-    // parse version
-    if matches!(input, b"HTTP/1.1" | b"HTTP/1.0") {
-        return Ok(&input[5..]);
+// rfc9112 7.1
+// chunk-size = 1*HEXDIG
+// chunk-ext = *( BWS ";" BWS chunk-ext-name [ BWS "=" BWS chunk-ext-val ] )
+pub fn parse_chunk_size(buf: &[u8]) -> Result<(usize, u64), ParseError> {
+    let (input, line) = read_line(buf)?;
+
+    let digits = line.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+    if digits == 0 {
+        return Err(ParseError::BadData);
+    }
+
+    let mut size: u64 = 0;
+    for b in &line[..digits] {
+        let d = (*b as char).to_digit(16).unwrap_or_default() as u64;
+        size = size
+            .checked_mul(16)
+            .and_then(|s| s.checked_add(d))
+            .ok_or(ParseError::TooLarge)?;
     }
 
-    if !input.starts_with(b"HTTP/") {
-        return Err(ParseError::BadVersion);
+    // chunk extensions are ignored, but must be well formed enough to skip
+    let ext = trim_ows(&line[digits..]);
+    if !ext.is_empty() && ext[0] != b';' {
+        return Err(ParseError::BadData);
     }
+    if ext.iter().any(|b| b.is_ascii_control() && *b != b'\t') {
+        return Err(ParseError::BadData);
+    }
+
+    Ok((buf.len() - input.len(), size))
+}
 
-    let version = &input[5..];
-    if version[0] < 48
-        || version[0] > 57
-        || version[1] != b'.'
-        || version[2] < 48
-        || version[2] > 57
-    {
-        return Err(ParseError::BadVersion);
+// rfc9112 7.1.2
+// trailer-section = *( field-line CRLF )
+pub fn parse_trailers<'a>(
+    buf: &'a [u8],
+    headers: &mut Vec<RawHeader<'a>>,
+) -> Result<usize, ParseError> {
+    let start = headers.len();
+    let input = parse_headers(buf, headers)?;
+
+    for h in &headers[start..] {
+        if FORBIDDEN_TRAILERS
+            .iter()
+            .any(|name| h.name.eq_ignore_ascii_case(name))
+        {
+            return Err(ParseError::BadTrailer);
+        }
     }
 
-    Ok(version)
+    Ok(buf.len() - input.len())
+}
+
+// rfc9112 2.3, HTTP-version = "HTTP/" DIGIT "." DIGIT, case-sensitive
+fn parse_http_version(input: &[u8]) -> Result<&[u8], ParseError> {
+    match input {
+        [b'H', b'T', b'T', b'P', b'/', major, b'.', minor]
+            if is_digit(*major) && is_digit(*minor) =>
+        {
+            Ok(&input[5..])
+        }
+        _ => Err(ParseError::BadVersion),
+    }
 }
 
 fn read_line(buf: &[u8]) -> Result<(&[u8], &[u8]), ParseError> {
@@ -298,6 +402,11 @@ fn must_split(buf: &[u8], pat: u8) -> Result<(&[u8], &[u8]), ParseError> {
     }
 }
 
+// rfc9110 5.6.2, token = 1*tchar
+pub(crate) fn is_token(input: &[u8]) -> bool {
+    !input.is_empty() && input.iter().all(|&b| b < 127 && TCHAR_TABLE[b as usize])
+}
+
 fn find_and_skip_byte<'a, 'b>(
     buf: &'a [u8],
     needle: u8,
@@ -487,6 +596,105 @@ Cookie: wp_ozh_wsa_visits=2; wp_ozh_wsa_visit_lasttime=xxxxxxxxxx; __utma=xxxxxx
         );
     }
 
+    #[test]
+    fn test_parse_chunk_size() {
+        assert_eq!(parse_chunk_size(b"1a\r\nhello"), Ok((4, 0x1a)));
+        assert_eq!(parse_chunk_size(b"0\r\n\r\n"), Ok((3, 0)));
+        assert_eq!(parse_chunk_size(b"A;name=value\r\n"), Ok((14, 10)));
+        assert_eq!(parse_chunk_size(b"5 ; ext\r\n"), Ok((9, 5)));
+
+        assert_eq!(parse_chunk_size(b"1a"), Err(ParseError::Incomplete));
+        assert_eq!(parse_chunk_size(b"\r\n"), Err(ParseError::BadData));
+        assert_eq!(parse_chunk_size(b"-1\r\n"), Err(ParseError::BadData));
+        assert_eq!(parse_chunk_size(b"5x\r\n"), Err(ParseError::BadData));
+        assert_eq!(parse_chunk_size(b"5;a\x00\r\n"), Err(ParseError::BadData));
+        assert_eq!(
+            parse_chunk_size(b"10000000000000000\r\n"),
+            Err(ParseError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn test_parse_trailers() {
+        let mut headers = Vec::new();
+        assert_eq!(parse_trailers(b"\r\nGET", &mut headers), Ok(2));
+        assert!(headers.is_empty());
+
+        let buf = b"Expires: never\r\nX-Checksum: abc\r\n\r\nGET";
+        assert_eq!(parse_trailers(buf, &mut headers), Ok(buf.len() - 3));
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[1].name, b"X-Checksum");
+        assert_eq!(headers[1].value, b"abc");
+
+        let mut headers = Vec::new();
+        assert_eq!(
+            parse_trailers(b"Expires: never\r\n", &mut headers),
+            Err(ParseError::Incomplete)
+        );
+
+        for buf in [
+            &b"Content-Length: 5\r\n\r\n"[..],
+            b"X-Checksum: abc\r\ntransfer-encoding: chunked\r\n\r\n",
+            b"Host: example.com\r\n\r\n",
+        ] {
+            let mut headers = Vec::new();
+            assert_eq!(
+                parse_trailers(buf, &mut headers),
+                Err(ParseError::BadTrailer)
+            );
+        }
+
+        let mut headers = Vec::new();
+        assert_eq!(
+            parse_trailers(b"Bad Name: x\r\n\r\n", &mut headers),
+            Err(ParseError::BadHeaderName)
+        );
+    }
+
+    #[test]
+    fn test_parse_http_version() {
+        assert_eq!(parse_http_version(b"HTTP/1.1"), Ok(&b"1.1"[..]));
+        assert_eq!(parse_http_version(b"HTTP/1.0"), Ok(&b"1.0"[..]));
+        assert_eq!(parse_http_version(b"HTTP/2.0"), Ok(&b"2.0"[..]));
+
+        for input in [
+            &b""[..],
+            b"HTTP/",
+            b"HTTP/1",
+            b"HTTP/1.",
+            b"HTTP/1.1 ",
+            b"HTTP/11.1",
+            b"http/1.1",
+            b"HTTP/x.1",
+        ] {
+            assert_eq!(parse_http_version(input), Err(ParseError::BadVersion));
+        }
+    }
+
+    #[test]
+    fn test_parse_request_method() {
+        for buf in [
+            &b"PATCH / HTTP/1.1\r\n\r\n"[..],
+            b"M-SEARCH * HTTP/1.1\r\n\r\n",
+        ] {
+            let mut req = RawRequest::new();
+            assert_eq!(parse_request(buf, &mut req), Ok(buf.len()));
+        }
+
+        for buf in [
+            &b"G@T / HTTP/1.1\r\n\r\n"[..],
+            b" / HTTP/1.1\r\n\r\n",
+            b"GET\t/ HTTP/1.1\r\n\r\n",
+            b"G\xc3\xa9T / HTTP/1.1\r\n\r\n",
+        ] {
+            let mut req = RawRequest::new();
+            assert_eq!(
+                parse_request(buf, &mut req),
+                Err(ParseError::UnsupportMethod)
+            );
+        }
+    }
+
     #[test]
     fn print_tchar_table() {
         print!("[");
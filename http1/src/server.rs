@@ -1,112 +1,320 @@
 use std::{
-    convert::Infallible,
-    fmt::{self},
+    collections::{BTreeMap, BTreeSet},
     future::Future,
-    io::Cursor,
-    pin::Pin,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use bytes::{Buf, Bytes, BytesMut};
+use bstr::ByteSlice;
+use bytes::{Buf, BufMut, BytesMut};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadHalf, WriteHalf},
+    net::{TcpListener, ToSocketAddrs},
     select,
-    sync::{mpsc, oneshot, watch},
+    sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore},
 };
 
 use crate::{
     body::Body,
-    error::{Error, ErrorKind},
-    http::{ContentLength, RequestInfo},
+    codec::{self, ReadTimeout},
+    error::Error,
+    http::{
+        header_values_contains_token, header_values_last_token, headers, ContentLength, Method,
+        RequestInfo, StatusCode, TlsInfo, Version,
+    },
 };
 use crate::{
     body::Sender,
     http::{Request, Response},
+    upgrade::{self, Upgraded},
 };
 
-use crate::parser::{parse_request, ParseError, RawRequest};
+use crate::parser::{parse_request, ParseError, RawRequest, DEFAULT_MAX_HEADERS};
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsAcceptor;
+
+const DEFAULT_BUFFER_CAPACITY: usize = 4 * 1024 + 64;
+const DEFAULT_MAX_HEADER_SIZE: usize = 4 * 1024;
+const DEFAULT_MAX_REQUEST_LINE: usize = 4 * 1024;
+const DEFAULT_PIPELINE_DEPTH: usize = 1;
+const NOT_CLOSING: u64 = u64::MAX;
+const DEFAULT_MAX_CONNECTIONS: usize = 10 * 1024;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_KEEP_ALIVE_HEADER_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_BODY_READ_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A request or response tagged with its position on the connection, the
+/// permit limits how many requests are in flight.
+type Sequenced<T> = (u64, T, OwnedSemaphorePermit);
+
+/// Bookkeeping for interim responses and upgrades, from the reader to the writer.
+enum Control {
+    // the request waits for `100 Continue` before sending its body
+    Expect(u64),
+    // its handler asked for the body
+    Continue(u64),
+    // the request asks for an upgrade, told whether it was switched to
+    Upgrade(u64, oneshot::Sender<bool>),
+    // an HTTP/1.0 request, its response can not be chunked
+    Http10(u64),
+    // a HEAD request, its response has no content
+    Head(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    buffer_capacity: usize,
+    max_header_size: usize,
+    max_headers: usize,
+    max_request_line: usize,
+    max_body_size: Option<usize>,
+    pipeline_depth: usize,
+    header_read_timeout: Duration,
+    keep_alive_header_timeout: Duration,
+    body_read_timeout: Duration,
+    idle_timeout: Duration,
+}
 
-const BUF_INIT_CAPACITY: usize = 4 * 1024 + 64;
-const MAX_HEADER_SIZE: usize = 4 * 1024;
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_request_line: DEFAULT_MAX_REQUEST_LINE,
+            max_body_size: None,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            header_read_timeout: DEFAULT_HEADER_READ_TIMEOUT,
+            keep_alive_header_timeout: DEFAULT_KEEP_ALIVE_HEADER_TIMEOUT,
+            body_read_timeout: DEFAULT_BODY_READ_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder {
+            config: ServerConfig::default(),
+        }
+    }
+}
+
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    /// Initial size of the read buffer of each connection.
+    pub fn buffer_capacity(mut self, capacity: usize) -> Self {
+        self.config.buffer_capacity = capacity;
+        self
+    }
+
+    /// Max bytes of the request line and header fields, answered with 431.
+    pub fn max_header_size(mut self, size: usize) -> Self {
+        self.config.max_header_size = size;
+        self
+    }
+
+    /// Max number of header fields, answered with 431.
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.config.max_headers = count;
+        self
+    }
+
+    /// Max bytes of the request line, answered with 414.
+    pub fn max_request_line(mut self, size: usize) -> Self {
+        self.config.max_request_line = size;
+        self
+    }
+
+    /// Max bytes of a request body. A larger Content-Length is answered
+    /// with 413, a chunked body fails once it grows over the limit.
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.config.max_body_size = Some(size);
+        self
+    }
+
+    /// Max requests read ahead of their responses on one connection, their
+    /// handlers run concurrently with `serve_pipelined` and `Server`, one
+    /// after another otherwise. Responses are always written in request order.
+    pub fn pipeline_depth(mut self, depth: usize) -> Self {
+        self.config.pipeline_depth = depth.max(1);
+        self
+    }
+
+    /// Time allowed to receive the first request header of a connection,
+    /// answered with 408 when part of it has arrived.
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.header_read_timeout = timeout;
+        self
+    }
+
+    /// Time allowed to receive a later request header once its first
+    /// bytes arrived, answered with 408.
+    pub fn keep_alive_header_timeout(mut self, timeout: Duration) -> Self {
+        self.config.keep_alive_header_timeout = timeout;
+        self
+    }
+
+    /// Time a request body may go without receiving any data.
+    pub fn body_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.body_read_timeout = timeout;
+        self
+    }
+
+    /// Time a kept-alive connection may wait for its next request, it is
+    /// closed silently afterwards.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> ServerConfig {
+        self.config
+    }
+}
 
 pub struct Pipeline {
-    request_rx: mpsc::Receiver<Request>,
-    response_tx: mpsc::Sender<(Response, oneshot::Sender<Result<(), Error>>)>,
+    request_rx: mpsc::Receiver<Sequenced<Request>>,
+    response_tx: mpsc::Sender<Sequenced<Response>>,
+    tls: Option<Arc<TlsInfo>>,
 }
 
 impl Pipeline {
     fn new(
-        request_rx: mpsc::Receiver<Request>,
-        response_tx: mpsc::Sender<(Response, oneshot::Sender<Result<(), Error>>)>,
+        request_rx: mpsc::Receiver<Sequenced<Request>>,
+        response_tx: mpsc::Sender<Sequenced<Response>>,
+        tls: Option<TlsInfo>,
     ) -> Self {
         Pipeline {
             request_rx,
             response_tx,
+            tls: tls.map(Arc::new),
         }
     }
 
-    async fn next(&mut self) -> Option<Request> {
-        self.request_rx.recv().await
+    async fn next(&mut self) -> Option<Sequenced<Request>> {
+        select! {
+            req = self.request_rx.recv() => req,
+
+            // writer is gone, no way to respond
+            _ = self.response_tx.closed() => None,
+        }
     }
 
-    async fn response(&mut self, resp: Response) -> Result<(), Error> {
-        let (done_tx, done_rx) = oneshot::channel();
+    async fn run<H>(mut self, mut handler: H)
+    where
+        H: Handler + Send + 'static,
+    {
+        while let Some((seq, mut req, permit)) = self.next().await {
+            req.tls = self.tls.clone();
 
-        self.response_tx.send((resp, done_tx)).await;
+            let resp = handler.call(req).await;
+            if self.response_tx.send((seq, resp, permit)).await.is_err() {
+                break;
+            }
+        }
+    }
 
-        done_rx.await;
+    async fn run_concurrent<H>(mut self, handler: H)
+    where
+        H: Handler + Clone + Send + 'static,
+    {
+        while let Some((seq, mut req, permit)) = self.next().await {
+            req.tls = self.tls.clone();
 
-        Ok(())
+            let mut handler = handler.clone();
+            let response_tx = self.response_tx.clone();
+
+            tokio::spawn(async move {
+                let resp = handler.call(req).await;
+                let _ = response_tx.send((seq, resp, permit)).await;
+            });
+        }
     }
 }
 
 struct Dispatcher<RW> {
     stream: RW,
-    request_tx: mpsc::Sender<Request>,
-    response_rx: mpsc::Receiver<(Response, oneshot::Sender<Result<(), Error>>)>,
+    config: ServerConfig,
+    request_tx: mpsc::Sender<Sequenced<Request>>,
+    response_tx: mpsc::Sender<Sequenced<Response>>,
+    response_rx: mpsc::Receiver<Sequenced<Response>>,
+    shutdown: Option<watch::Receiver<bool>>,
 }
 
 impl<RW> Dispatcher<RW>
 where
-    RW: AsyncRead + AsyncWrite + Unpin,
+    RW: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
     pub fn new(
         stream: RW,
-        request_tx: mpsc::Sender<Request>,
-        response_rx: mpsc::Receiver<(Response, oneshot::Sender<Result<(), Error>>)>,
+        config: ServerConfig,
+        request_tx: mpsc::Sender<Sequenced<Request>>,
+        response_tx: mpsc::Sender<Sequenced<Response>>,
+        response_rx: mpsc::Receiver<Sequenced<Response>>,
+        shutdown: Option<watch::Receiver<bool>>,
     ) -> Self {
         Dispatcher {
-            stream: stream,
+            stream,
+            config,
             request_tx,
+            response_tx,
             response_rx,
+            shutdown,
         }
     }
 
     async fn dispatch(self) -> Result<(), Error> {
         let Dispatcher {
             stream,
+            config,
             request_tx,
+            response_tx,
             response_rx,
+            shutdown,
         } = self;
 
-        let (signal_tx, signal_rx) = mpsc::channel::<bool>(1);
+        // sequence number of the response that closes the connection
+        let closing = Arc::new(AtomicU64::new(NOT_CLOSING));
+
+        let (control_tx, control_rx) = mpsc::channel(1);
 
         let (read_half, write_half) = tokio::io::split(stream);
 
-        let reader = StreamReader::new(read_half, signal_tx, request_tx);
+        let reader = StreamReader::new(
+            read_half,
+            config,
+            request_tx,
+            response_tx,
+            control_tx,
+            shutdown.clone(),
+            closing.clone(),
+        );
 
-        let writer = StreamWriter::new(write_half, signal_rx, response_rx);
+        let writer = StreamWriter::new(write_half, response_rx, control_rx, closing);
 
         let ret = tokio::join!(reader.run(), writer.run());
 
-        Ok(())
-    }
+        // the connection counts as open for shutdown until here
+        drop(shutdown);
 
-    async fn response(&mut self, resp: Response) -> Result<(), Error> {
-        let buf = resp.header_buf();
-
-        self.stream.write_all(&buf).await?;
-
-        self.stream.flush().await?;
+        // the connection now belongs to the handler of the upgraded request
+        if let (Ok(Some((read_half, buffer, pending))), Ok(Some(write_half))) = ret {
+            let io = read_half.unsplit(write_half);
+            pending.fulfill(Upgraded::new(Box::new(io), buffer.freeze()));
+        }
 
         Ok(())
     }
@@ -115,32 +323,61 @@ where
 pub struct StreamReader<R> {
     stream: ReadHalf<R>,
     buffer: BytesMut,
-    signal_tx: mpsc::Sender<bool>,
-    request_tx: mpsc::Sender<Request>,
+    config: ServerConfig,
+    seq: u64,
+    permits: Arc<Semaphore>,
+    request_tx: mpsc::Sender<Sequenced<Request>>,
+    // error responses skip the handler
+    response_tx: mpsc::Sender<Sequenced<Response>>,
+    control_tx: mpsc::Sender<Control>,
+    shutdown: Option<watch::Receiver<bool>>,
+    closing: Arc<AtomicU64>,
+    // the request that took over the connection
+    upgraded: Option<upgrade::Pending>,
 }
 
 impl<R: AsyncRead> StreamReader<R> {
     fn new(
         stream: ReadHalf<R>,
-        signal_tx: mpsc::Sender<bool>,
-        request_tx: mpsc::Sender<Request>,
+        config: ServerConfig,
+        request_tx: mpsc::Sender<Sequenced<Request>>,
+        response_tx: mpsc::Sender<Sequenced<Response>>,
+        control_tx: mpsc::Sender<Control>,
+        shutdown: Option<watch::Receiver<bool>>,
+        closing: Arc<AtomicU64>,
     ) -> Self {
         StreamReader {
+            shutdown,
+            closing,
             stream,
-            signal_tx,
+            seq: 0,
+            permits: Arc::new(Semaphore::new(config.pipeline_depth)),
             request_tx,
-            buffer: BytesMut::with_capacity(BUF_INIT_CAPACITY),
+            response_tx,
+            control_tx,
+            buffer: BytesMut::with_capacity(config.buffer_capacity),
+            config,
+            upgraded: None,
         }
     }
 
-    async fn run(mut self) -> Result<(), Error> {
+    /// Serve requests until the connection is done, the read half and
+    /// buffered bytes are handed back when a request upgraded it.
+    async fn run(mut self) -> Result<Option<(ReadHalf<R>, BytesMut, upgrade::Pending)>, Error> {
         let r_tx = self.request_tx.clone();
+        let c_tx = self.control_tx.clone();
 
         loop {
             select! {
+                // a finished request is not lost to the writer going away
+                biased;
+
                 ret = self.do_read() => {
                     match ret {
-                        Ok(_) => {}
+                        Ok(true) => {}
+                        Ok(false) => {
+                            break;
+                        }
                         Err(err) => {
                             return Err(err);
                         }
@@ -148,71 +385,233 @@ impl<R: AsyncRead> StreamReader<R> {
                 }
 
                 _ = r_tx.closed() => {
-                    return Ok(());
+                    break;
+                }
+
+                // writer is gone, the connection is done
+                _ = c_tx.closed() => {
+                    break;
                 }
             }
         }
 
-        Ok(())
+        Ok(self
+            .upgraded
+            .map(|pending| (self.stream, self.buffer, pending)))
     }
 
-    async fn do_read(&mut self) -> Result<(), Error> {
+    /// Read and dispatch one request, false when the connection should
+    /// not read any more.
+    async fn do_read(&mut self) -> Result<bool, Error> {
+        // shutdown only stops a connection between requests
+        let mut shutdown = self.shutdown.clone();
+        let permit = select! {
+            permit = self.wait_request() => permit?,
+
+            _ = shutdown_signaled(&mut shutdown) => {
+                // the last response tells the client the connection is going away
+                if let Some(last) = self.seq.checked_sub(1) {
+                    self.closing.store(last, Ordering::SeqCst);
+                }
+                return Ok(false);
+            }
+        };
+
         let mut info = RequestInfo::new();
 
-        match self.read_request_header(&mut info).await {
-            Ok(mut req) => {
-                let (body, sender) = self.build_request_body(&info);
+        let mut req = match self.read_request_header(&mut info).await {
+            Ok(req) => req,
+            Err(err) => {
+                let status = match err.parse_error() {
+                    Some(err) => error_status(err),
+                    // only answer a request that has started to arrive
+                    None if err.is_timeout() && !self.buffer.is_empty() => {
+                        Some(StatusCode::REQUEST_TIMEOUT)
+                    }
+                    None => None,
+                };
+                if let Some(status) = status {
+                    self.send_error_response(status, permit).await;
+                }
+                return Err(err);
+            }
+        };
 
-                req.body = body;
+        let (body, sender) = self.build_request_body(&info);
 
-                // println!("=> {:?}", &req);
+        req.body = body;
 
-                self.request_tx.send(req).await.unwrap();
+        // the client waits for `100 Continue` before sending the body
+        let demand = match &sender {
+            Some(_) if info.expect_continue => Some(req.body.on_demand()),
+            _ => None,
+        };
 
-                if let Some(tx) = sender {
-                    self.read_request_body(&info, tx).await?;
-                }
+        let seq = self.seq;
+        self.seq += 1;
+
+        if demand.is_some() {
+            let _ = self.control_tx.send(Control::Expect(seq)).await;
+        }
+
+        if req.version == Version::V1_0 {
+            let _ = self.control_tx.send(Control::Http10(seq)).await;
+        }
+
+        if req.method == Method::HEAD {
+            let _ = self.control_tx.send(Control::Head(seq)).await;
+        }
+
+        // no more requests are read after this one
+        if info.should_close {
+            self.closing.store(seq, Ordering::SeqCst);
+        }
 
-                Ok(())
+        // no more requests are read until the upgrade is decided
+        let upgrade = if info.upgrade {
+            let (pending, on_upgrade) = upgrade::pending();
+            req.upgrade = Some(on_upgrade);
+
+            let (tx, rx) = oneshot::channel();
+            let _ = self.control_tx.send(Control::Upgrade(seq, tx)).await;
+            Some((pending, rx))
+        } else {
+            None
+        };
+
+        self.request_tx
+            .send((seq, req, permit))
+            .await
+            .map_err(|_e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, ""))?;
+
+        if let Some(demand) = demand {
+            if demand.await.is_err() {
+                // the writer closes the connection after its response
+                return Ok(false);
             }
 
-            Err(err) => {
-                self.signal_tx.send(true).await.unwrap();
-                Err(err)
+            let _ = self.control_tx.send(Control::Continue(seq)).await;
+        }
+
+        if let Some(tx) = sender {
+            if let Err(err) = self.read_request_body(&info, &tx).await {
+                // the handler learns why its body is cut short, the
+                // connection can not go on either way
+                let _ = tx.send(Err(err)).await;
+                return Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted).into());
             }
         }
+
+        if let Some((pending, switched)) = upgrade {
+            match switched.await {
+                Ok(true) => {
+                    self.upgraded = Some(pending);
+                    return Ok(false);
+                }
+                Ok(false) => {}
+                Err(_e) => return Ok(false),
+            }
+        }
+
+        Ok(!info.should_close)
+    }
+
+    /// Answer a request that could not be parsed, the connection is closed after it.
+    async fn send_error_response(&mut self, status: StatusCode, permit: OwnedSemaphorePermit) {
+        let mut resp = Response::with_status(status);
+        resp.header_map.set(headers::CONNECTION, headers::CLOSE);
+        resp.header_map.set(headers::CONTENT_LENGTH, b"0");
+
+        let seq = self.seq;
+        self.seq += 1;
+
+        let _ = self.response_tx.send((seq, resp, permit)).await;
+    }
+
+    /// Wait for room in the pipeline and the first bytes of the next request.
+    async fn wait_request(&mut self) -> Result<OwnedSemaphorePermit, Error> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, ""))?;
+
+        if self.buffer.is_empty() {
+            let timeout = if self.seq == 0 {
+                self.config.header_read_timeout
+            } else {
+                self.config.idle_timeout
+            };
+
+            tokio::time::timeout(timeout, codec::read_buf(&mut self.stream, &mut self.buffer))
+                .await
+                .map_err(|_elapsed| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+        }
+
+        Ok(permit)
     }
 
     async fn read_request_header(&mut self, info: &mut RequestInfo) -> Result<Request, Error> {
+        let timeout = if self.seq == 0 {
+            self.config.header_read_timeout
+        } else {
+            self.config.keep_alive_header_timeout
+        };
+
+        match tokio::time::timeout(timeout, self.parse_request_header(info)).await {
+            Ok(ret) => ret,
+            Err(_elapsed) => Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()),
+        }
+    }
+
+    async fn parse_request_header(&mut self, info: &mut RequestInfo) -> Result<Request, Error> {
         loop {
+            // an empty line ahead of the request line is skipped by the parser
+            let start = if self.buffer.starts_with(b"\r\n") {
+                2
+            } else {
+                0
+            };
+            let line = memchr::memchr(b'\n', &self.buffer[start..]).unwrap_or(self.buffer.len());
+            if line > self.config.max_request_line {
+                return Err(ParseError::RequestLineTooLong.into());
+            }
+
             let mut req = RawRequest::new();
             match parse_request(&self.buffer[..], &mut req) {
                 Ok(parsed) => {
-                    let ret = Request::from_raw_request(req, info);
+                    if parsed > self.config.max_header_size
+                        || req.headers.len() > self.config.max_headers
+                    {
+                        return Err(ParseError::TooLarge.into());
+                    }
+
+                    let req = Request::from_raw_request(req, info)?;
                     self.buffer.advance(parsed);
-                    return ret;
+
+                    if let (ContentLength::Sized(len), Some(max)) =
+                        (&info.content_length, self.config.max_body_size)
+                    {
+                        if *len > max {
+                            return Err(ParseError::ContentTooLarge.into());
+                        }
+                    }
+
+                    return Ok(req);
                 }
                 Err(ParseError::Incomplete) => {
-                    if self.buffer.len() > MAX_HEADER_SIZE {
-                        return Err(Error::new(ErrorKind::Protocol, ParseError::TooLarge));
+                    if self.buffer.len() > self.config.max_header_size {
+                        return Err(ParseError::TooLarge.into());
                     }
                 }
                 Err(err) => {
-                    return Err(Error::new(ErrorKind::Protocol, err));
+                    return Err(err.into());
                 }
             }
 
             let n = self.stream.read_buf(&mut self.buffer).await?;
             if n == 0 {
-                if self.buffer.len() > 8 * 1024 {
-                    return Err(ParseError::TooLarge.into());
-                }
-
-                if self.buffer.capacity() == self.buffer.len() {
-                    self.buffer.reserve(1024);
-                    continue;
-                }
-
                 return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "").into());
             }
         }
@@ -240,162 +639,599 @@ impl<R: AsyncRead> StreamReader<R> {
         }
     }
 
-    async fn read_request_body(&mut self, info: &RequestInfo, sender: Sender) -> Result<(), Error> {
+    async fn read_request_body(
+        &mut self,
+        info: &RequestInfo,
+        sender: &Sender,
+    ) -> Result<(), Error> {
+        let stream = &mut ReadTimeout::new(&mut self.stream, self.config.body_read_timeout);
+        let buffer = &mut self.buffer;
+
         match info.content_length {
             ContentLength::None => Ok(()),
-            ContentLength::Close => self.read_request_close_body(sender).await,
-            ContentLength::Sized(len) => self.read_request_sized_body(len, sender).await,
-            ContentLength::Chunked => self.read_request_chunked_body(sender).await,
+            ContentLength::Close => codec::read_close_body(stream, buffer, sender).await,
+            ContentLength::Sized(len) => codec::read_sized_body(stream, buffer, len, sender).await,
+            ContentLength::Chunked => {
+                let limit = self.config.max_body_size;
+                codec::read_chunked_body(stream, buffer, limit, sender).await
+            }
         }
     }
+}
 
-    async fn read_request_sized_body(&mut self, len: usize, sender: Sender) -> Result<(), Error> {
-        let mut need = len;
+pub struct StreamWriter<W> {
+    stream: BufWriter<WriteHalf<W>>,
+    response_rx: mpsc::Receiver<Sequenced<Response>>,
+    // responses finished ahead of their turn, keyed by sequence number
+    reorder: BTreeMap<u64, (Response, OwnedSemaphorePermit)>,
+    next_seq: u64,
+    closing: Arc<AtomicU64>,
+    control_rx: mpsc::Receiver<Control>,
+    // requests whose body has not been asked for yet
+    expecting: BTreeSet<u64>,
+    // requests to send `100 Continue` for once it is their turn
+    continues: BTreeSet<u64>,
+    // requests asking for an upgrade
+    upgrades: BTreeMap<u64, oneshot::Sender<bool>>,
+    // HTTP/1.0 requests
+    http10: BTreeSet<u64>,
+    // HEAD requests
+    heads: BTreeSet<u64>,
+}
 
+impl<W: AsyncWrite> StreamWriter<W> {
+    fn new(
+        stream: WriteHalf<W>,
+        response_rx: mpsc::Receiver<Sequenced<Response>>,
+        control_rx: mpsc::Receiver<Control>,
+        closing: Arc<AtomicU64>,
+    ) -> Self {
+        StreamWriter {
+            stream: BufWriter::new(stream),
+            response_rx,
+            reorder: BTreeMap::new(),
+            next_seq: 0,
+            closing,
+            control_rx,
+            expecting: BTreeSet::new(),
+            continues: BTreeSet::new(),
+            upgrades: BTreeMap::new(),
+            http10: BTreeSet::new(),
+            heads: BTreeSet::new(),
+        }
+    }
+
+    /// Write responses in order, the write half is handed back once one
+    /// switched protocols.
+    async fn run(mut self) -> Result<Option<WriteHalf<W>>, Error> {
+        // ends when the reader and all handlers are done
         loop {
-            let mut done = false;
+            select! {
+                // a request is registered before its handler can answer it
+                biased;
 
-            let to_send = if self.buffer.len() >= need {
-                done = true;
-                self.buffer.split_to(need).freeze()
-            } else {
-                need -= self.buffer.len();
-                self.buffer.split().freeze()
-            };
+                Some(msg) = self.control_rx.recv() => match msg {
+                    Control::Expect(seq) => {
+                        self.expecting.insert(seq);
+                    }
+                    Control::Continue(seq) => {
+                        // too late when the final response is already out
+                        if self.expecting.remove(&seq) {
+                            self.continues.insert(seq);
+                        }
+                    }
+                    Control::Upgrade(seq, switched) => {
+                        self.upgrades.insert(seq, switched);
+                    }
+                    Control::Http10(seq) => {
+                        self.http10.insert(seq);
+                    }
+                    Control::Head(seq) => {
+                        self.heads.insert(seq);
+                    }
+                },
 
-            select! {
-                closed = sender.closed() => {
-                    // when req.body been dropped, just drop rest body
-                    break;
-                }
+                ret = self.response_rx.recv() => match ret {
+                    Some((seq, resp, permit)) => {
+                        self.reorder.insert(seq, (resp, permit));
+                    }
+                    None => return Ok(None),
+                },
+            }
+
+            if self.continues.remove(&self.next_seq) {
+                self.stream
+                    .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                    .await?;
+                self.stream.flush().await?;
+            }
 
-                _ = sender.send(Ok(to_send)) => {
-                    if done {
-                        return Ok(());
+            while let Some((mut resp, permit)) = self.reorder.remove(&self.next_seq) {
+                let switching = resp.status_code == StatusCode::SWITCHING_PROTOCOLS;
+                let upgrade = match self.upgrades.remove(&self.next_seq) {
+                    Some(switched) if switching => {
+                        // no content, the new protocol starts right after
+                        self.stream.write_all(&resp.header_buf()).await?;
+                        self.stream.flush().await?;
+
+                        drop(permit);
+                        let _ = switched.send(true);
+
+                        return Ok(Some(self.stream.into_inner()));
                     }
+                    upgrade => upgrade,
+                };
+
+                // a request answered without asking for its body may or may
+                // not be followed by it, the connection can not be reused
+                let expecting = self.expecting.remove(&self.next_seq);
+                let http10 = self.http10.remove(&self.next_seq);
+                let head = self.heads.remove(&self.next_seq);
+
+                let connection = resp.header_map.get(headers::CONNECTION);
+                let mut close = connection
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|h| header_values_contains_token(&h.value, headers::CLOSE));
+
+                if close || expecting || self.closing.load(Ordering::SeqCst) == self.next_seq {
+                    resp.header_map.set(headers::CONNECTION, headers::CLOSE);
+                    close = true;
+                } else if http10 && connection.is_none() {
+                    // rfc9112 9.3, HTTP/1.0 clients are told the connection persists
+                    resp.header_map
+                        .set(headers::CONNECTION, headers::KEEP_ALIVE);
+                }
+
+                // framing is broken after a failed write
+                let delimited = self.write_response(resp, http10, head).await?;
+                self.next_seq += 1;
+
+                if let Some(switched) = upgrade {
+                    let _ = switched.send(false);
+                }
+
+                // the connection is not reused, or the body was delimited by closing it
+                if close || delimited {
+                    return Ok(None);
+                }
+
+                // let the reader go on with the next request
+                drop(permit);
+
+                if self.continues.remove(&self.next_seq) {
+                    self.stream
+                        .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                        .await?;
+                    self.stream.flush().await?;
                 }
             }
+        }
+    }
 
-            self.read_buf().await?;
+    /// Write one response, true when its body is delimited by closing
+    /// the connection.
+    async fn write_response(
+        &mut self,
+        resp: Response,
+        http10: bool,
+        head: bool,
+    ) -> Result<bool, Error> {
+        let mut resp = resp;
+        let mut close = false;
+
+        // rfc9110 6.4.1, these responses end with their header block
+        let no_content = head
+            || resp.status_code.is_informational()
+            || resp.status_code == StatusCode::NO_CONTENT
+            || resp.status_code == StatusCode::NOT_MODIFIED;
+
+        // rfc9112 7, HTTP/1.0 does not know transfer-codings
+        if http10 {
+            resp.header_map.remove(headers::TRANSFER_ENCODING.as_bstr());
         }
 
-        // consume request body
-        loop {
-            if self.buffer.len() >= need {
-                self.buffer.advance(need);
-                break;
-            } else {
-                self.buffer.advance(self.buffer.len());
+        // HEAD and 304 declare the length of the content they stand for
+        let stands_for = head || resp.status_code == StatusCode::NOT_MODIFIED;
+
+        let mut length = None;
+        let chunked = if let Some(codings) = resp.header_map.get(headers::TRANSFER_ENCODING) {
+            // rfc9112 6.1, only a final chunked coding tells where the body ends
+            let chunked = codings.last().is_some_and(|h| {
+                header_values_last_token(&h.value).eq_ignore_ascii_case(headers::CHUNKED)
+            });
+            if !chunked {
+                resp.header_map
+                    .append(headers::TRANSFER_ENCODING, headers::CHUNKED);
+            }
+            // rfc9112 6.2, never sent along with a transfer-coding
+            resp.header_map.remove(headers::CONTENT_LENGTH.as_bstr());
+            true
+        } else if let Some(len) = codec::declared_length(&resp.header_map)? {
+            // a length other than the body's would desync the connection
+            if !stands_for && resp.body.size_hint().is_some_and(|n| n != len) {
+                return Err(codec::length_mismatch());
+            }
+            length = Some(len);
+            false
+        } else if let Some(len) = resp.body.size_hint() {
+            // rfc9110 8.6, never sent with 1xx and 204, nor with HEAD and 304
+            // unless the handler gave the content they stand for
+            let declare = match stands_for {
+                true => resp.body.is_once(),
+                false => {
+                    !resp.status_code.is_informational()
+                        && resp.status_code != StatusCode::NO_CONTENT
+                }
             };
+            if declare {
+                resp.header_map
+                    .set(headers::CONTENT_LENGTH, len.to_string().as_bytes());
+            }
+            length = Some(len);
+            false
+        } else if no_content {
+            false
+        } else if http10 {
+            // the body ends with the connection
+            resp.header_map.set(headers::CONNECTION, headers::CLOSE);
+            close = true;
+            false
+        } else {
+            // without a known length, the body is framed with chunked transfer-coding
+            resp.header_map
+                .set(headers::TRANSFER_ENCODING, headers::CHUNKED);
+            true
+        };
 
-            self.read_buf().await?;
+        let data = resp.header_buf();
+
+        if no_content {
+            self.stream.write_all(&data).await?;
+            self.stream.flush().await?;
+
+            return Ok(false);
         }
 
-        Ok(())
-    }
+        // fast path, header block and the whole body in one write
+        if let Some(body) = resp.body.take_once() {
+            let mut buf = BytesMut::with_capacity(data.len() + body.len());
+            buf.put_slice(&data);
+            buf.put_slice(&body);
 
-    async fn read_request_close_body(&mut self, tx: Sender) -> Result<(), Error> {
-        unimplemented!()
-    }
+            self.stream.write_all(&buf).await?;
+            self.stream.flush().await?;
 
-    async fn read_request_chunked_body(&mut self, tx: Sender) -> Result<(), Error> {
-        unimplemented!()
-    }
+            return Ok(close);
+        }
 
-    async fn read_buf(&mut self) -> Result<(), Error> {
-        let n = self.stream.read_buf(&mut self.buffer).await?;
-        if n == 0 {
-            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "").into());
+        self.stream.write_all(&data).await?;
+
+        if chunked {
+            codec::write_chunked_body(&mut self.stream, &mut resp.body).await?;
+        } else if let Some(len) = length {
+            codec::write_sized_body(&mut self.stream, &mut resp.body, len).await?;
+        } else {
+            codec::write_close_body(&mut self.stream, &mut resp.body).await?;
         }
 
-        Ok(())
+        self.stream.flush().await?;
+
+        Ok(close)
     }
 }
 
-pub struct StreamWriter<W> {
-    stream: WriteHalf<W>,
-    signal_rx: mpsc::Receiver<bool>,
-    response_rx: mpsc::Receiver<(Response, oneshot::Sender<Result<(), Error>>)>,
+fn error_status(err: ParseError) -> Option<StatusCode> {
+    let status = match err {
+        ParseError::Incomplete => return None,
+        ParseError::TooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+        ParseError::ContentTooLarge => StatusCode::CONTENT_TOO_LARGE,
+        ParseError::RequestLineTooLong => StatusCode::URI_TOO_LONG,
+        ParseError::UnsupportVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+        ParseError::UnsupportTransferCoding => StatusCode::NOT_IMPLEMENTED,
+        ParseError::UnsupportExpectation => StatusCode::EXPECTATION_FAILED,
+        _ => StatusCode::BAD_REQUEST,
+    };
+
+    Some(status)
 }
 
-impl<W: AsyncWrite> StreamWriter<W> {
-    fn new(
-        stream: WriteHalf<W>,
-        signal_rx: mpsc::Receiver<bool>,
-        response_rx: mpsc::Receiver<(Response, oneshot::Sender<Result<(), Error>>)>,
-    ) -> Self {
-        StreamWriter {
-            stream,
-            signal_rx,
-            response_rx,
+pub async fn serve<IO>(io: IO, handler: impl Handler + Send + 'static) -> Result<(), Error>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    serve_with_config(io, ServerConfig::default(), handler).await
+}
+
+pub async fn serve_with_config<IO>(
+    io: IO,
+    config: ServerConfig,
+    handler: impl Handler + Send + 'static,
+) -> Result<(), Error>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    serve_connection(io, config, |pipeline| pipeline.run(handler), None, None).await
+}
+
+/// Like `serve_with_config`, but each request read ahead is handled by its
+/// own clone of the handler, so up to `pipeline_depth` of them run at once.
+pub async fn serve_pipelined<IO>(
+    io: IO,
+    config: ServerConfig,
+    handler: impl Handler + Clone + Send + 'static,
+) -> Result<(), Error>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let run = |pipeline: Pipeline| pipeline.run_concurrent(handler);
+    serve_connection(io, config, run, None, None).await
+}
+
+/// Like `serve_with_config`, over TLS terminated with `acceptor`.
+#[cfg(feature = "tls")]
+pub async fn serve_tls<IO>(
+    io: IO,
+    acceptor: &TlsAcceptor,
+    config: ServerConfig,
+    handler: impl Handler + Send + 'static,
+) -> Result<(), Error>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let run = |pipeline: Pipeline| pipeline.run(handler);
+    serve_tls_connection(io, acceptor.clone(), config, run, None).await
+}
+
+#[cfg(feature = "tls")]
+async fn serve_tls_connection<IO, F>(
+    io: IO,
+    acceptor: TlsAcceptor,
+    config: ServerConfig,
+    run: impl FnOnce(Pipeline) -> F,
+    shutdown: Option<watch::Receiver<bool>>,
+) -> Result<(), Error>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    F: Future<Output = ()> + Send + 'static,
+{
+    // the handshake gets as long as the first request header
+    let (stream, info) = tokio::time::timeout(config.header_read_timeout, acceptor.accept(io))
+        .await
+        .map_err(|_elapsed| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    serve_connection(stream, config, run, shutdown, Some(info)).await
+}
+
+/// `run` drives the handler side of the connection's pipeline.
+async fn serve_connection<IO, F>(
+    io: IO,
+    config: ServerConfig,
+    run: impl FnOnce(Pipeline) -> F,
+    shutdown: Option<watch::Receiver<bool>>,
+    tls: Option<TlsInfo>,
+) -> Result<(), Error>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (request_tx, request_rx) = mpsc::channel(config.pipeline_depth);
+    let (response_tx, response_rx) = mpsc::channel(config.pipeline_depth);
+
+    let pipeline = Pipeline::new(request_rx, response_tx.clone(), tls);
+
+    let dispatcher = Dispatcher::new(io, config, request_tx, response_tx, response_rx, shutdown);
+
+    tokio::spawn(run(pipeline));
+
+    dispatcher.dispatch().await
+}
+
+async fn shutdown_signaled(shutdown: &mut Option<watch::Receiver<bool>>) {
+    if let Some(rx) = shutdown {
+        if rx.wait_for(|triggered| *triggered).await.is_ok() {
+            return;
         }
     }
 
-    async fn run(mut self) -> Result<(), Error> {
-        loop {
-            select! {
-                _ = self.signal_rx.recv() => {
-                    return Ok(());
-                }
+    // never triggered
+    std::future::pending::<()>().await
+}
 
-                ret = self.response_rx.recv() => {
-                    match ret {
-                        Some((resp, tx)) => {
-                            let ret = self.write_response(resp).await;
+/// Graceful shutdown for connections served through it. Once triggered, each
+/// connection finishes the requests it has read, marks the last response
+/// with `Connection: close` and closes.
+pub struct Shutdown {
+    tx: watch::Sender<bool>,
+}
 
-                            tx.send(ret);
-                        }
-                        None => {
-                            return Ok(());
-                        }
-                    }
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-                }
-            }
-        }
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, _rx) = watch::channel(false);
+        Shutdown { tx }
+    }
 
-        Ok(())
+    /// Like `serve_with_config`, but the connection stops on shutdown.
+    pub fn serve<IO>(
+        &self,
+        io: IO,
+        config: ServerConfig,
+        handler: impl Handler + Send + 'static,
+    ) -> impl Future<Output = Result<(), Error>>
+    where
+        IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let run = |pipeline: Pipeline| pipeline.run(handler);
+        serve_connection(io, config, run, Some(self.tx.subscribe()), None)
     }
 
-    async fn write_response(&mut self, resp: Response) -> Result<(), Error> {
-        let data = resp.header_buf();
-        self.stream.write_all(&data).await;
-        self.stream.flush().await;
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
 
-        Ok(())
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Trigger shutdown and wait for every connection to close, false when
+    /// `timeout` passed first.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.trigger();
+
+        tokio::time::timeout(timeout, self.tx.closed())
+            .await
+            .is_ok()
     }
 }
 
-pub async fn serve<IO>(io: IO, handler: impl Handler + Send + 'static) -> Result<(), Error>
-where
-    IO: AsyncRead + AsyncWrite + Unpin,
-{
-    let mut handler = handler;
+/// Accepts connections from a listener and serves each of them with a
+/// clone of the handler, pipelined requests get a clone each as with
+/// `serve_pipelined`.
+pub struct Server {
+    listener: TcpListener,
+    config: ServerConfig,
+    // a permit is held by each connection being served
+    connections: Arc<Semaphore>,
+    shutdown_timeout: Duration,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+}
+
+impl Server {
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr).await?;
+
+        Ok(Server::from_listener(listener))
+    }
 
-    let (request_tx, request_rx) = mpsc::channel(1);
-    let (response_tx, response_rx) = mpsc::channel(1);
+    pub fn from_listener(listener: TcpListener) -> Self {
+        Server {
+            listener,
+            config: ServerConfig::default(),
+            connections: Arc::new(Semaphore::new(DEFAULT_MAX_CONNECTIONS)),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
 
-    let mut pipeline = Pipeline::new(request_rx, response_tx);
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
 
-    let dispatcher = Dispatcher::new(io, request_tx, response_rx);
+    /// Max connections served at once, accepting pauses at the limit.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.connections = Arc::new(Semaphore::new(max.max(1)));
+        self
+    }
+
+    /// Time given to open connections to finish after shutdown.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Terminate TLS on accepted connections, their requests are marked
+    /// with `Scheme::HTTPS`.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub async fn run(self, handler: impl Handler + Clone + Send + 'static) {
+        self.run_until(handler, std::future::pending()).await
+    }
+
+    /// Serve until `signal` resolves, then shut down gracefully.
+    pub async fn run_until(
+        self,
+        handler: impl Handler + Clone + Send + 'static,
+        signal: impl Future<Output = ()>,
+    ) {
+        let shutdown = Shutdown::new();
+
+        tokio::pin!(signal);
+
+        let mut backoff = ACCEPT_BACKOFF_MIN;
 
-    tokio::spawn(async move {
         loop {
-            match pipeline.next().await {
-                Some(req) => {
-                    let resp = handler.call(req).await;
-                    pipeline.response(resp).await.unwrap();
-                }
-                None => {
-                    break;
+            let accept = async {
+                let permit = self.connections.clone().acquire_owned().await;
+                (permit, self.listener.accept().await)
+            };
+
+            let (permit, ret) = select! {
+                ret = accept => ret,
+                _ = &mut signal => break,
+            };
+
+            let stream = match ret {
+                Ok((stream, _remote_addr)) => stream,
+                Err(err) => {
+                    // the failed connection is already gone, try the next one
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::ConnectionAborted
+                            | std::io::ErrorKind::ConnectionReset
+                            | std::io::ErrorKind::ConnectionRefused
+                            | std::io::ErrorKind::Interrupted
+                    ) {
+                        continue;
+                    }
+
+                    // most likely out of file descriptors, wait for some to be released
+                    select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = &mut signal => break,
+                    }
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                    continue;
                 }
-            }
+            };
+            backoff = ACCEPT_BACKOFF_MIN;
+
+            let _ = stream.set_nodelay(true);
+
+            let config = self.config.clone();
+            let run = {
+                let handler = handler.clone();
+                |pipeline: Pipeline| pipeline.run_concurrent(handler)
+            };
+            let shutdown_rx = Some(shutdown.tx.subscribe());
+            #[cfg(feature = "tls")]
+            let tls = self.tls.clone();
+
+            tokio::spawn(async move {
+                #[cfg(feature = "tls")]
+                let _ = match tls {
+                    Some(acceptor) => {
+                        serve_tls_connection(stream, acceptor, config, run, shutdown_rx).await
+                    }
+                    None => serve_connection(stream, config, run, shutdown_rx, None).await,
+                };
+                #[cfg(not(feature = "tls"))]
+                let _ = serve_connection(stream, config, run, shutdown_rx, None).await;
+
+                drop(permit);
+            });
         }
-    });
 
-    dispatcher.dispatch().await.unwrap();
+        drop(self.listener);
 
-    Ok(())
+        shutdown.drain(self.shutdown_timeout).await;
+    }
 }
 
 #[async_trait::async_trait]
@@ -416,25 +1252,1295 @@ where
 
 #[cfg(test)]
 mod test {
-    use tokio::net::TcpListener;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::{Barrier, Semaphore},
+    };
+
+    use bstr::ByteSlice;
+    use bytes::Bytes;
+
+    use crate::{
+        body::Body,
+        http::{HeaderMap, Request, Response},
+    };
+
+    use super::{
+        serve, serve_pipelined, serve_with_config, Handler, Server, ServerConfig, Shutdown,
+    };
+    use crate::http::StatusCode;
+    use crate::parser::ParseError;
+    use crate::upgrade;
+
+    async fn read_head<R: AsyncRead + Unpin>(stream: &mut R) -> String {
+        let mut buf = Vec::new();
+
+        while !buf.ends_with(b"\r\n\r\n") {
+            let mut b = [0u8; 1];
+            if stream.read(&mut b).await.unwrap() == 0 {
+                break;
+            }
+            buf.push(b[0]);
+        }
 
-    use crate::http::{Request, Response};
+        String::from_utf8(buf).unwrap()
+    }
 
-    use super::serve;
+    async fn echo_body(mut req: Request) -> Response {
+        let mut body = Vec::new();
+        while let Some(d) = req.body.data().await.unwrap() {
+            body.extend_from_slice(&d);
+        }
+
+        let mut resp = Response::new();
+        resp.header_map.append(b"Content-Length", b"0");
+        resp.header_map.append(b"X-Body", &body);
+        resp
+    }
 
     #[tokio::test]
     async fn test_serve() {
-        let listener = TcpListener::bind("127.0.0.1:8080").await.unwrap();
+        let server = Server::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
 
-        loop {
-            let connection = listener.accept().await.unwrap();
+        tokio::spawn(server.run(|_req: Request| Box::pin(async move { Response::new() })));
 
-            tokio::spawn(async move {
-                serve(connection.0, |req: Request| {
-                    Box::pin(async move { Response::new() })
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_server_max_connections() {
+        let server = Server::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .max_connections(1);
+        let addr = server.local_addr().unwrap();
+        let connections = server.connections.clone();
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run_until(
+            |req: Request| Box::pin(async move { path_response(&req) }),
+            async move {
+                let _ = rx.await;
+            },
+        ));
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(b"GET /1 HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(read_head(&mut first).await.contains("X-Path: /1\r\n"));
+
+        // the first connection holds the only permit, so the second one
+        // waits in the backlog until it is gone
+        assert_eq!(connections.available_permits(), 0);
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second.write_all(b"GET /2 HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(connections.available_permits(), 0);
+
+        drop(first);
+        assert!(read_head(&mut second).await.contains("X-Path: /2\r\n"));
+
+        // shutdown closes the kept-alive connection and stops accepting
+        tx.send(()).unwrap();
+        running.await.unwrap();
+
+        let mut buf = Vec::new();
+        second.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_body() {
+        let (mut client, server) = tokio::io::duplex(64);
+
+        tokio::spawn(serve(server, |req: Request| Box::pin(echo_body(req))));
+
+        // chunk extensions and trailers are skipped, the next request is still parsed
+        client
+            .write_all(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                5;name=value\r\nhello\r\n\
+                6\r\n world\r\n\
+                0\r\nX-Checksum: abc\r\n\r\n\
+                POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                3\r\nfoo\r\n0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.contains("X-Body: hello world\r\n"), "{}", head);
+
+        let head = read_head(&mut client).await;
+        assert!(head.contains("X-Body: foo\r\n"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_request_trailers() {
+        let (mut client, server) = tokio::io::duplex(64);
+
+        tokio::spawn(serve(server, |mut req: Request| {
+            Box::pin(async move {
+                let mut resp = Response::new();
+                resp.header_map.append(b"Content-Length", b"0");
+                match req.body.trailers().await {
+                    Ok(trailers) => {
+                        if let Some(checksum) = trailers.get(b"X-Checksum") {
+                            resp.header_map.append(b"X-Checksum", &checksum[0].value);
+                        }
+                    }
+                    Err(_err) => resp.status_code = StatusCode::BAD_REQUEST,
+                }
+                resp
+            })
+        }));
+
+        client
+            .write_all(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                5\r\nhello\r\n0\r\nX-Checksum: abc\r\n\r\n\
+                POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                5\r\nhello\r\n0\r\nContent-Length: 5\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.contains("X-Checksum: abc\r\n"), "{}", head);
+
+        // framing fields are not allowed in trailers
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 400 "), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_body_dropped() {
+        let (mut client, server) = tokio::io::duplex(64);
+
+        tokio::spawn(serve(server, |req: Request| {
+            Box::pin(async move {
+                drop(req);
+                let mut resp = Response::new();
+                resp.header_map.append(b"Content-Length", b"0");
+                resp
+            })
+        }));
+
+        client
+            .write_all(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                a\r\n0123456789\r\na\r\n0123456789\r\n0\r\n\r\n\
+                GET / HTTP/1.1\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_chunked_response_body() {
+        let (mut client, server) = tokio::io::duplex(64);
+
+        tokio::spawn(serve(server, |_req: Request| {
+            Box::pin(async move {
+                let (tx, body) = Body::channel();
+
+                tokio::spawn(async move {
+                    for d in ["hello", "", " world"] {
+                        tx.send(Ok(Bytes::from(d))).await.unwrap();
+                    }
+                });
+
+                let mut resp = Response::new();
+                resp.body = body;
+                resp
+            })
+        }));
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{}", head);
+
+        let mut buf = vec![0u8; 26];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_head_response() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        tokio::spawn(serve(server, |req: Request| {
+            Box::pin(async move {
+                let (tx, body) = Body::channel();
+
+                tokio::spawn(async move {
+                    let _ = tx.send(Ok(Bytes::from("hello"))).await;
+                });
+
+                let mut resp = path_response(&req);
+                resp.header_map.remove(b"Content-Length".as_bstr());
+                resp.body = body;
+                resp
+            })
+        }));
+
+        client
+            .write_all(b"HEAD /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        // the next response follows right after the header block
+        let head = read_head(&mut client).await;
+        assert!(head.contains("X-Path: /a\r\n"), "{}", head);
+        assert!(!head.contains("Transfer-Encoding"), "{}", head);
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("X-Path: /b\r\n"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_response_length_without_content() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        tokio::spawn(serve(server, |req: Request| {
+            Box::pin(async move {
+                let mut resp = match req.uri.as_bstr().as_bytes() {
+                    b"/empty" => Response::with_status(StatusCode::NO_CONTENT),
+                    b"/cached" => Response::with_status(StatusCode::NOT_MODIFIED),
+                    _ => Response::new(),
+                };
+                resp.header_map.append(b"X-Path", req.uri.as_bstr());
+                if resp.status_code == StatusCode::OK {
+                    resp.body = Body::with_bytes(Bytes::from("hello"));
+                }
+                resp
+            })
+        }));
+
+        client
+            .write_all(
+                b"HEAD /a HTTP/1.1\r\n\r\nDELETE /empty HTTP/1.1\r\n\r\n\
+                GET /cached HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        // the length of what GET would send, without sending it
+        let head = read_head(&mut client).await;
+        assert!(head.contains("Content-Length: 5\r\n"), "{}", head);
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", head);
+        assert!(!head.contains("Content-Length"), "{}", head);
+
+        // the length of the cached content is not known from an empty body
+        let head = read_head(&mut client).await;
+        assert!(
+            head.starts_with("HTTP/1.1 304 Not Modified\r\n"),
+            "{}",
+            head
+        );
+        assert!(!head.contains("Content-Length"), "{}", head);
+
+        let head = read_head(&mut client).await;
+        assert!(head.contains("X-Path: /b\r\n"), "{}", head);
+
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_response_transfer_coding() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        tokio::spawn(serve(server, |_req: Request| {
+            Box::pin(async move {
+                let (tx, body) = Body::channel();
+
+                tokio::spawn(async move {
+                    let _ = tx.send(Ok(Bytes::from("hello"))).await;
+                });
+
+                let mut resp = Response::new();
+                resp.header_map.append(b"Transfer-Encoding", b"gzip");
+                resp.header_map.append(b"Content-Length", b"5");
+                resp.body = body;
+                resp
+            })
+        }));
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+        // chunked is added as the final coding to delimit the body
+        let head = read_head(&mut client).await;
+        assert!(head.contains("Transfer-Encoding: gzip\r\n"), "{}", head);
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{}", head);
+        assert!(!head.contains("Content-Length"), "{}", head);
+
+        let mut buf = vec![0u8; 15];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"5\r\nhello\r\n0\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_response_length_mismatch() {
+        for (path, sent) in [
+            // known to be wrong before anything is written
+            ("/once", ""),
+            ("/short", "hello"),
+            ("/long", "hel"),
+        ] {
+            let (mut client, server) = tokio::io::duplex(1024);
+
+            tokio::spawn(serve(server, |req: Request| {
+                Box::pin(async move {
+                    let mut resp = Response::new();
+                    match req.uri.as_bstr().as_bytes() {
+                        b"/once" => {
+                            resp.header_map.append(b"Content-Length", b"3");
+                            resp.body = Body::with_bytes("hello");
+                        }
+                        path => {
+                            let len: &[u8] = if path == b"/short" { b"10" } else { b"3" };
+                            resp.header_map.append(b"Content-Length", len);
+
+                            let (tx, body) = Body::channel();
+                            tokio::spawn(async move {
+                                let _ = tx.send(Ok(Bytes::from("hello"))).await;
+                            });
+                            resp.body = body;
+                        }
+                    }
+                    resp
                 })
+            }));
+
+            let req = format!("GET {} HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n", path);
+            client.write_all(req.as_bytes()).await.unwrap();
+
+            // the connection closes rather than going out of sync
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            let resp = String::from_utf8(buf).unwrap();
+            let body = resp.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+            assert_eq!(body, sent, "{}: {}", path, resp);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_no_content_response() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        tokio::spawn(serve(server, |req: Request| {
+            Box::pin(async move {
+                let (tx, body) = Body::channel();
+
+                tokio::spawn(async move {
+                    let _ = tx.send(Ok(Bytes::from("hello"))).await;
+                });
+
+                let mut resp = Response::with_status(StatusCode::NO_CONTENT);
+                resp.header_map.append(b"X-Path", req.uri.as_bstr());
+                resp.body = body;
+                resp
+            })
+        }));
+
+        client
+            .write_all(b"DELETE /a HTTP/1.1\r\n\r\nDELETE /b HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", head);
+        assert!(!head.contains("Transfer-Encoding"), "{}", head);
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", head);
+        assert!(head.contains("X-Path: /b\r\n"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() {
+        let handler = |req: Request| {
+            Box::pin(async move {
+                let mut resp = path_response(&req);
+                if req.uri.as_bstr() == "/close" {
+                    resp.header_map.append(b"Connection", b"close");
+                }
+                resp
+            })
+        };
+
+        async fn serve_all(
+            handler: impl Handler + Clone + Send + 'static,
+            request: &[u8],
+        ) -> String {
+            let (mut client, server) = tokio::io::duplex(1024);
+            tokio::spawn(serve(server, handler));
+
+            client.write_all(request).await.unwrap();
+
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            String::from_utf8(buf).unwrap()
+        }
+
+        // the client closes, nothing after its request is served
+        let resp = serve_all(
+            handler,
+            b"GET /a HTTP/1.1\r\nConnection: close\r\n\r\nGET /b HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(resp.contains("Connection: close\r\n"), "{}", resp);
+        assert!(!resp.contains("X-Path: /b"), "{}", resp);
+
+        // the handler closes
+        let resp = serve_all(
+            handler,
+            b"GET /a HTTP/1.1\r\n\r\nGET /close HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(resp.contains("X-Path: /a\r\n"), "{}", resp);
+        assert!(resp.contains("X-Path: /close\r\n"), "{}", resp);
+        assert_eq!(resp.matches("Connection: close\r\n").count(), 1, "{}", resp);
+        assert!(!resp.contains("X-Path: /b"), "{}", resp);
+
+        // HTTP/1.0 clients asking for keep-alive get it confirmed
+        let resp = serve_all(
+            handler,
+            b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\n",
+        )
+        .await;
+        let (a, b) = resp.split_at(resp.rfind("HTTP/1.1 ").unwrap());
+        assert!(a.contains("Connection: keep-alive\r\n"), "{}", resp);
+        assert!(b.contains("Connection: close\r\n"), "{}", resp);
+
+        // HTTP/1.1 connections persist without saying so
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve(server, handler));
+
+        client
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        for path in ["/a", "/b"] {
+            let head = read_head(&mut client).await;
+            assert!(head.contains(&format!("X-Path: {}\r\n", path)), "{}", head);
+            assert!(!head.contains("Connection"), "{}", head);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http10() {
+        let handler = |req: Request| {
+            Box::pin(async move {
+                let (tx, body) = Body::channel();
+
+                tokio::spawn(async move {
+                    tx.send(Ok(Bytes::from("hello"))).await.unwrap();
+                    let mut trailers = HeaderMap::new();
+                    trailers.append(b"X-Checksum", b"abc");
+                    tx.send_trailers(trailers).await.unwrap();
+                });
+
+                let mut resp = Response::new();
+                if req.uri.as_bstr() == "/stream" {
+                    resp.body = body;
+                } else {
+                    resp.header_map.append(b"Content-Length", b"0");
+                }
+                resp
+            })
+        };
+
+        // no keep-alive, the connection is closed after the response
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve(server, handler));
+
+        client
+            .write_all(b"GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        let resp = String::from_utf8(buf).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(resp.contains("Connection: close\r\n"), "{}", resp);
+        assert_eq!(resp.matches("HTTP/1.1").count(), 1, "{}", resp);
+
+        // kept alive, until a body of unknown length has to be delimited
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve(server, handler));
+
+        client
+            .write_all(
+                b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+                GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(!head.contains("Connection: close"), "{}", head);
+
+        let head = read_head(&mut client).await;
+        assert!(!head.contains("Transfer-Encoding"), "{}", head);
+        assert!(head.contains("Connection: close\r\n"), "{}", head);
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_response_trailers() {
+        let (mut client, server) = tokio::io::duplex(64);
+
+        tokio::spawn(serve(server, |_req: Request| {
+            Box::pin(async move {
+                let (tx, body) = Body::channel();
+
+                tokio::spawn(async move {
+                    tx.send(Ok(Bytes::from("hello"))).await.unwrap();
+
+                    let mut trailers = HeaderMap::new();
+                    trailers.append(b"X-Checksum", b"abc");
+                    tx.send_trailers(trailers).await.unwrap();
+                });
+
+                let mut resp = Response::new();
+                resp.body = body;
+                resp
+            })
+        }));
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{}", head);
+
+        let mut buf = vec![0u8; 32];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"5\r\nhello\r\n0\r\nX-Checksum: abc\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_sized_response_body() {
+        let (mut client, server) = tokio::io::duplex(64);
+
+        tokio::spawn(serve(server, |req: Request| {
+            Box::pin(async move {
+                let mut resp = Response::new();
+                if req.uri.as_bstr() == "/hello" {
+                    resp.body = Body::with_bytes("hello world");
+                }
+                resp
+            })
+        }));
+
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.contains("Content-Length: 11\r\n"), "{}", head);
+        assert!(!head.contains("Transfer-Encoding"), "{}", head);
+
+        let mut buf = vec![0u8; 11];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"hello world");
+
+        let head = read_head(&mut client).await;
+        assert!(head.contains("Content-Length: 0\r\n"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_bad_size() {
+        let (mut client, server) = tokio::io::duplex(64);
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(serve(server, move |mut req: Request| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                let mut failed = None;
+                while let Some(d) = req.body.data().await.transpose() {
+                    if let Err(err) = d {
+                        failed = err.parse_error();
+                        break;
+                    }
+                }
+                let _ = tx.send(failed);
+
+                let mut resp = Response::new();
+                resp.header_map.append(b"Content-Length", b"0");
+                resp
+            })
+        }));
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        // the handler learns the body is broken, and the connection ends
+        // after its response since the request framing is lost
+        assert_eq!(rx.recv().await, Some(Some(ParseError::BadData)));
+        assert_eq!(buf, b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    fn path_response(req: &Request) -> Response {
+        let mut resp = Response::new();
+        resp.header_map.append(b"Content-Length", b"0");
+        resp.header_map.append(b"X-Path", req.uri.as_bstr());
+        resp
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_ordered_responses() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        let config = ServerConfig::builder().pipeline_depth(4).build();
+
+        // /a is held back until the handlers of /b and /c are done
+        let done = Arc::new(Semaphore::new(0));
+
+        tokio::spawn(serve_pipelined(server, config, move |req: Request| {
+            let done = done.clone();
+            Box::pin(async move {
+                let resp = path_response(&req);
+                if req.uri.as_bstr() == "/a" {
+                    done.acquire_many(2).await.unwrap().forget();
+                } else {
+                    done.add_permits(1);
+                }
+                resp
+            })
+        }));
+
+        client
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        for path in ["/a", "/b", "/c"] {
+            let head = tokio::time::timeout(Duration::from_secs(5), read_head(&mut client))
                 .await
-            });
+                .unwrap();
+            assert!(head.contains(&format!("X-Path: {}\r\n", path)), "{}", head);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_depth_one() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let max = max_running.clone();
+
+        tokio::spawn(serve(server, move |req: Request| {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            Box::pin(async move {
+                let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(n, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                path_response(&req)
+            })
+        }));
+
+        client
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        for path in ["/a", "/b", "/c"] {
+            let head = read_head(&mut client).await;
+            assert!(head.contains(&format!("X-Path: {}\r\n", path)), "{}", head);
+        }
+
+        assert_eq!(max.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sequential_handler_state() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        // not Clone, one instance answers every request in turn
+        struct Counter(usize);
+        let mut counter = Counter(0);
+
+        let config = ServerConfig::builder().pipeline_depth(4).build();
+        tokio::spawn(serve_with_config(server, config, move |req: Request| {
+            counter.0 += 1;
+            let mut resp = path_response(&req);
+            resp.header_map
+                .append(b"X-Count", counter.0.to_string().as_bytes());
+            Box::pin(async move { resp })
+        }));
+
+        client
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        for count in 1..=3 {
+            let head = read_head(&mut client).await;
+            assert!(
+                head.contains(&format!("X-Count: {}\r\n", count)),
+                "{}",
+                head
+            );
         }
     }
+
+    async fn exchange(request: &[u8]) -> String {
+        exchange_with(ServerConfig::default(), request).await
+    }
+
+    async fn exchange_with(config: ServerConfig, request: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(16 * 1024);
+
+        tokio::spawn(serve_with_config(server, config, |mut req: Request| {
+            Box::pin(async move {
+                let mut resp = Response::new();
+                resp.header_map.append(b"Content-Length", b"0");
+                // a body that fails to read is told apart from the server's own answers
+                while let Some(d) = req.body.data().await.transpose() {
+                    if let Err(err) = d {
+                        let cause = err.parse_error().map(|e| e.to_string());
+                        resp.status_code = StatusCode::INTERNAL_SERVER_ERROR;
+                        resp.header_map
+                            .append(b"X-Body-Error", cause.unwrap_or_default().as_bytes());
+                        break;
+                    }
+                }
+                resp
+            })
+        }));
+
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_error_responses() {
+        let resp = exchange(b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", resp);
+        assert!(resp.contains("Connection: close\r\n"), "{}", resp);
+
+        let mut req = b"GET / HTTP/1.1\r\nX-Long: ".to_vec();
+        req.extend_from_slice(&[b'a'; 8 * 1024]);
+        let resp = exchange(&req).await;
+        assert!(resp.starts_with("HTTP/1.1 431 "), "{}", resp);
+
+        let resp = exchange(b"GET / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 501 "), "{}", resp);
+
+        let resp = exchange(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 501 "), "{}", resp);
+
+        let resp =
+            exchange(b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 413 "), "{}", resp);
+
+        // a malformed version is a bad request, a well-formed one other
+        // than 1.x is not supported
+        for version in ["HTTP/x.1", "HTTP/1", "HTTP/1.10", "http/1.1"] {
+            let resp = exchange(format!("GET / {}\r\n\r\n", version).as_bytes()).await;
+            assert!(resp.starts_with("HTTP/1.1 400 "), "{}: {}", version, resp);
+        }
+
+        for version in ["HTTP/2.0", "HTTP/0.9"] {
+            let resp = exchange(format!("GET / {}\r\n\r\n", version).as_bytes()).await;
+            assert!(resp.starts_with("HTTP/1.1 505 "), "{}: {}", version, resp);
+        }
+
+        // a closed connection is not answered
+        assert_eq!(exchange(b"GET / HTTP/1.1\r\n").await, "");
+    }
+
+    #[tokio::test]
+    async fn test_request_smuggling() {
+        // the body of the first request would be the second one to a
+        // server trusting Content-Length
+        let resp = exchange(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n\
+            0\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", resp);
+        assert!(resp.contains("Connection: close\r\n"), "{}", resp);
+        assert_eq!(resp.matches("HTTP/1.1").count(), 1, "{}", resp);
+
+        let resp = exchange(
+            b"POST / HTTP/1.1\r\nContent-Length: 0\r\nContent-Length: 34\r\n\r\n\
+            GET /smuggled HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", resp);
+        assert_eq!(resp.matches("HTTP/1.1").count(), 1, "{}", resp);
+    }
+
+    #[tokio::test]
+    async fn test_error_response_after_pipelined() {
+        let resp = exchange(b"GET / HTTP/1.1\r\n\r\nGET /\x01 HTTP/1.1\r\n\r\n").await;
+
+        let (first, second) = resp.split_once("\r\n\r\n").unwrap();
+        assert!(first.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(second.starts_with("HTTP/1.1 400 "), "{}", resp);
+    }
+
+    #[tokio::test]
+    async fn test_server_limits() {
+        let config = || ServerConfig::builder().buffer_capacity(8);
+
+        let resp = exchange_with(
+            config().max_request_line(16).build(),
+            b"GET /long/enough/path HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(resp.starts_with("HTTP/1.1 414 "), "{}", resp);
+
+        let resp = exchange_with(
+            config().max_header_size(32).build(),
+            b"GET / HTTP/1.1\r\nX-Long: aaaaaaaaaaaaaaaa\r\n\r\n",
+        )
+        .await;
+        assert!(resp.starts_with("HTTP/1.1 431 "), "{}", resp);
+
+        let resp = exchange_with(
+            config().max_headers(2).build(),
+            b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n",
+        )
+        .await;
+        assert!(resp.starts_with("HTTP/1.1 431 "), "{}", resp);
+
+        let resp = exchange_with(
+            config().max_body_size(4).build(),
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .await;
+        assert!(resp.starts_with("HTTP/1.1 413 "), "{}", resp);
+
+        let resp = exchange_with(
+            config().max_body_size(4).build(),
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n",
+        )
+        .await;
+        // the length is not known up front, so the handler's read fails instead
+        assert!(resp.starts_with("HTTP/1.1 500 "), "{}", resp);
+        assert!(
+            resp.contains("X-Body-Error: ContentTooLarge\r\n"),
+            "{}",
+            resp
+        );
+
+        // within every limit
+        let resp = exchange_with(
+            config()
+                .max_request_line(16)
+                .max_headers(2)
+                .max_body_size(4)
+                .build(),
+            b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nhell",
+        )
+        .await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+    }
+
+    #[tokio::test]
+    async fn test_header_timeouts() {
+        let timeout = Duration::from_millis(50);
+        let config = || {
+            ServerConfig::builder()
+                .header_read_timeout(timeout)
+                .keep_alive_header_timeout(timeout)
+                .idle_timeout(timeout)
+                .build()
+        };
+
+        // trickling the first request
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_with_config(server, config(), |req: Request| {
+            Box::pin(echo_body(req))
+        }));
+
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).await.unwrap();
+        assert!(
+            buf.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            buf
+        );
+
+        // an idle connection is closed without a response
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_with_config(server, config(), |req: Request| {
+            Box::pin(echo_body(req))
+        }));
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "");
+
+        // a later request that stalls
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_with_config(server, config(), |req: Request| {
+            Box::pin(echo_body(req))
+        }));
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nHost")
+            .await
+            .unwrap();
+        read_head(&mut client).await;
+
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).await.unwrap();
+        assert!(buf.starts_with("HTTP/1.1 408 "), "{}", buf);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_body_read_timeout() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        let config = ServerConfig::builder()
+            .body_read_timeout(Duration::from_secs(5))
+            .build();
+
+        tokio::spawn(serve_with_config(server, config, |mut req: Request| {
+            Box::pin(async move {
+                let mut resp = Response::new();
+                resp.header_map.append(b"Content-Length", b"0");
+                let mut received = 0;
+                while let Some(d) = req.body.data().await.transpose() {
+                    match d {
+                        Ok(d) => received += d.len(),
+                        Err(err) => {
+                            assert!(err.is_timeout(), "{:?}", err);
+                            resp.status_code = StatusCode::REQUEST_TIMEOUT;
+                            break;
+                        }
+                    }
+                }
+                resp.header_map
+                    .append(b"X-Received", received.to_string().as_bytes());
+                resp
+            })
+        }));
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello")
+            .await
+            .unwrap();
+
+        // a slow body is fine as long as data keeps coming
+        for _ in 0..2 {
+            tokio::time::advance(Duration::from_secs(4)).await;
+            client.write_all(b" ").await.unwrap();
+        }
+
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).await.unwrap();
+        assert!(buf.starts_with("HTTP/1.1 408 "), "{}", buf);
+        assert!(buf.contains("X-Received: 7\r\n"), "{}", buf);
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        tokio::spawn(serve(server, |req: Request| {
+            Box::pin(async move {
+                if req.uri.as_bstr() == "/reject" {
+                    let mut resp = Response::with_status(StatusCode::EXPECTATION_FAILED);
+                    resp.header_map.append(b"Content-Length", b"0");
+                    return resp;
+                }
+                echo_body(req).await
+            })
+        }));
+
+        // the body is only sent once the server asks for it
+        client
+            .write_all(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut client).await;
+        assert_eq!(head, "HTTP/1.1 100 Continue\r\n\r\n");
+
+        client.write_all(b"hello").await.unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("X-Body: hello\r\n"), "{}", head);
+
+        // rejected without reading the body, the connection is not reused
+        client
+            .write_all(
+                b"POST /reject HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).await.unwrap();
+        assert!(buf.starts_with("HTTP/1.1 417 "), "{}", buf);
+        assert!(buf.contains("Connection: close\r\n"), "{}", buf);
+        assert!(!buf.contains("100 Continue"), "{}", buf);
+
+        let resp = exchange(b"POST / HTTP/1.1\r\nExpect: something\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 417 "), "{}", resp);
+
+        // HTTP/1.0 requests may have their expectations ignored
+        for expect in ["something", "100-continue"] {
+            let req = format!(
+                "POST / HTTP/1.0\r\nExpect: {}\r\nContent-Length: 0\r\n\r\n",
+                expect
+            );
+            let resp = exchange(req.as_bytes()).await;
+            assert!(resp.starts_with("HTTP/1.1 200 "), "{}", resp);
+            assert!(!resp.contains("100 Continue"), "{}", resp);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upgrade() {
+        let handler = |mut req: Request| {
+            Box::pin(async move {
+                let on_upgrade = match upgrade::on(&mut req) {
+                    Some(on_upgrade) if req.uri.as_bstr() == "/echo" => on_upgrade,
+                    _ => return path_response(&req),
+                };
+
+                tokio::spawn(async move {
+                    let mut io = on_upgrade.await.unwrap();
+                    let mut buf = [0u8; 4];
+                    io.read_exact(&mut buf).await.unwrap();
+                    buf.make_ascii_uppercase();
+                    io.write_all(&buf).await.unwrap();
+                });
+
+                let mut resp = Response::with_status(StatusCode::SWITCHING_PROTOCOLS);
+                resp.header_map.append(b"Connection", b"upgrade");
+                resp.header_map.append(b"Upgrade", b"echo");
+                resp
+            })
+        };
+
+        // bytes sent along with the request go to the new protocol
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve(server, handler));
+
+        client
+            .write_all(b"GET /echo HTTP/1.1\r\nConnection: upgrade\r\nUpgrade: echo\r\n\r\nping")
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(
+            head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+            "{}",
+            head
+        );
+        assert!(!head.contains("Content-Length"), "{}", head);
+
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"PING");
+
+        // declined, the connection goes on with HTTP/1.1
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve(server, handler));
+
+        client
+            .write_all(
+                b"GET /a HTTP/1.1\r\nConnection: upgrade\r\nUpgrade: echo\r\n\r\n\
+                GET /b HTTP/1.1\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.contains("X-Path: /a\r\n"), "{}", head);
+        let head = read_head(&mut client).await;
+        assert!(head.contains("X-Path: /b\r\n"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let shutdown = Shutdown::new();
+
+        // the in-flight request is answered before the connection closes
+        let (mut client, server) = tokio::io::duplex(1024);
+        let started = Arc::new(Barrier::new(2));
+        let handler_started = started.clone();
+        tokio::spawn(
+            shutdown.serve(server, ServerConfig::default(), move |req: Request| {
+                let started = handler_started.clone();
+                Box::pin(async move {
+                    started.wait().await;
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    path_response(&req)
+                })
+            }),
+        );
+
+        // an idle connection just closes
+        let (mut idle, server) = tokio::io::duplex(1024);
+        tokio::spawn(
+            shutdown.serve(server, ServerConfig::default(), |req: Request| {
+                Box::pin(echo_body(req))
+            }),
+        );
+        idle.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        read_head(&mut idle).await;
+
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\n").await.unwrap();
+        started.wait().await;
+
+        assert!(shutdown.drain(Duration::from_secs(5)).await);
+        assert!(shutdown.is_triggered());
+
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).await.unwrap();
+        assert!(buf.starts_with("HTTP/1.1 200 OK\r\n"), "{}", buf);
+        assert!(buf.contains("Connection: close\r\n"), "{}", buf);
+        assert!(buf.contains("X-Path: /a\r\n"), "{}", buf);
+
+        let mut buf = String::new();
+        idle.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_graceful_shutdown_deadline() {
+        let shutdown = Shutdown::new();
+
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let mut started_tx = Some(started_tx);
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(
+            shutdown.serve(server, ServerConfig::default(), move |_req: Request| {
+                if let Some(tx) = started_tx.take() {
+                    let _ = tx.send(());
+                }
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    Response::new()
+                })
+            }),
+        );
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        started_rx.await.unwrap();
+
+        // the request in flight outlasts the deadline
+        assert!(!shutdown.drain(Duration::from_secs(5)).await);
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_tls() {
+        use tokio_rustls::rustls::{self, pki_types::ServerName};
+
+        use crate::http::Scheme;
+        use crate::tls::TlsAcceptor;
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("http1-test-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), certified.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), certified.signing_key.serialize_pem()).unwrap();
+
+        let acceptor =
+            TlsAcceptor::from_pem_files(dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let server = Server::bind("127.0.0.1:0").await.unwrap().tls(acceptor);
+        let addr = server.local_addr().unwrap();
+
+        tokio::spawn(server.run(|req: Request| {
+            Box::pin(async move {
+                assert_eq!(req.scheme(), Scheme::HTTPS);
+                let info = req.tls_info().unwrap();
+
+                let mut resp = Response::new();
+                resp.header_map.append(
+                    b"X-Server-Name",
+                    info.server_name.as_deref().unwrap_or("").as_bytes(),
+                );
+                resp.header_map
+                    .append(b"X-Alpn", info.alpn_protocol.as_deref().unwrap_or(b""));
+                resp
+            })
+        }));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let mut config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("X-Server-Name: localhost\r\n"), "{}", head);
+        assert!(head.contains("X-Alpn: http/1.1\r\n"), "{}", head);
+
+        // plain http on a tls listener never gets a response
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(!read_head(&mut stream).await.starts_with("HTTP/1.1"));
+    }
 }
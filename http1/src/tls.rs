@@ -0,0 +1,60 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+
+pub use tokio_rustls::rustls;
+
+use crate::{error::Error, http::TlsInfo};
+
+const ALPN_HTTP_1_1: &[u8] = b"http/1.1";
+
+/// Terminates TLS on accepted connections.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    inner: tokio_rustls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// Load a certificate chain and its private key from PEM files,
+    /// `http/1.1` is offered with ALPN.
+    pub fn from_pem_files(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self, Error> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?.ok_or_else(
+            || std::io::Error::new(std::io::ErrorKind::InvalidData, "no private key"),
+        )?;
+
+        let mut config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        config.alpn_protocols = vec![ALPN_HTTP_1_1.to_vec()];
+
+        Ok(TlsAcceptor::from_config(Arc::new(config)))
+    }
+
+    /// Use an existing rustls configuration as is.
+    pub fn from_config(config: Arc<rustls::ServerConfig>) -> Self {
+        TlsAcceptor {
+            inner: tokio_rustls::TlsAcceptor::from(config),
+        }
+    }
+
+    /// Run the handshake, along with what it negotiated.
+    pub(crate) async fn accept<IO>(&self, io: IO) -> Result<(TlsStream<IO>, TlsInfo), Error>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self.inner.accept(io).await?;
+
+        let (_, conn) = stream.get_ref();
+        let info = TlsInfo {
+            server_name: conn.server_name().map(|name| name.to_string()),
+            alpn_protocol: conn.alpn_protocol().map(|proto| proto.to_vec()),
+        };
+
+        Ok((stream, info))
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::oneshot,
+};
+
+use crate::{error::Error, http::Request};
+
+/// The raw connection an upgraded request took over.
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// Connection handed over after a `101 Switching Protocols` response. Bytes
+/// the client sent after the request are read before the connection's own.
+pub struct Upgraded {
+    io: Box<dyn Io>,
+    read_buf: Bytes,
+}
+
+impl Upgraded {
+    pub(crate) fn new(io: Box<dyn Io>, read_buf: Bytes) -> Self {
+        Upgraded { io, read_buf }
+    }
+
+    /// The raw connection and the bytes already read from it.
+    pub fn into_parts(self) -> (Box<dyn Io>, Bytes) {
+        (self.io, self.read_buf)
+    }
+}
+
+impl fmt::Debug for Upgraded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upgraded")
+            .field("read_buf", &self.read_buf.len())
+            .finish()
+    }
+}
+
+impl AsyncRead for Upgraded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+
+        if !this.read_buf.is_empty() {
+            let n = std::cmp::min(this.read_buf.len(), buf.remaining());
+            buf.put_slice(&this.read_buf[..n]);
+            this.read_buf.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Upgraded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Resolves to the connection once the response to the request was
+/// `101 Switching Protocols`, fails for any other response.
+#[derive(Debug)]
+pub struct OnUpgrade {
+    rx: oneshot::Receiver<Upgraded>,
+}
+
+impl Future for OnUpgrade {
+    type Output = Result<Upgraded, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|ret| ret.map_err(|_e| std::io::Error::other("connection not upgraded").into()))
+    }
+}
+
+/// Hands the connection to the matching `OnUpgrade`.
+pub(crate) struct Pending {
+    tx: oneshot::Sender<Upgraded>,
+}
+
+impl Pending {
+    pub(crate) fn fulfill(self, upgraded: Upgraded) {
+        let _ = self.tx.send(upgraded);
+    }
+}
+
+pub(crate) fn pending() -> (Pending, OnUpgrade) {
+    let (tx, rx) = oneshot::channel();
+
+    (Pending { tx }, OnUpgrade { rx })
+}
+
+/// Take the upgrade of a request asking for one with `Connection: upgrade`.
+/// The handler answers with `101 Switching Protocols`, and the connection
+/// is no longer served as HTTP/1.1 afterwards.
+pub fn on(req: &mut Request) -> Option<OnUpgrade> {
+    req.upgrade.take()
+}
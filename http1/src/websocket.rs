@@ -0,0 +1,919 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bstr::ByteSlice;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    error::{Error, ErrorKind},
+    http::{header_values_contains_token, headers, Method, Request, Response, StatusCode},
+    upgrade::{self, OnUpgrade, Upgraded},
+};
+
+// rfc6455 1.3
+const ACCEPT_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const VERSION: &[u8] = b"13";
+const SEC_WEBSOCKET_KEY: &[u8] = b"Sec-WebSocket-Key";
+const SEC_WEBSOCKET_VERSION: &[u8] = b"Sec-WebSocket-Version";
+const SEC_WEBSOCKET_ACCEPT: &[u8] = b"Sec-WebSocket-Accept";
+const WEBSOCKET: &[u8] = b"websocket";
+
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+const DEFAULT_BUFFER_CAPACITY: usize = 4 * 1024;
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Status codes of a close frame, rfc6455 7.4.1.
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+
+    /// Whether a peer may send the code, the others are reserved.
+    pub(crate) fn is_valid(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    max_message_size: usize,
+    max_frame_size: usize,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl WebSocketConfig {
+    pub fn builder() -> WebSocketConfigBuilder {
+        WebSocketConfigBuilder {
+            config: WebSocketConfig::default(),
+        }
+    }
+}
+
+pub struct WebSocketConfigBuilder {
+    config: WebSocketConfig,
+}
+
+impl WebSocketConfigBuilder {
+    /// Max bytes of a received message, all of its fragments together. The
+    /// connection is closed with 1009 when it grows over the limit.
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.config.max_message_size = size;
+        self
+    }
+
+    /// Max payload bytes of a sent frame, larger messages are fragmented.
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.config.max_frame_size = size.max(1);
+        self
+    }
+
+    pub fn build(self) -> WebSocketConfig {
+        self.config
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Bytes),
+    Ping(Bytes),
+    Pong(Bytes),
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    BadFrame,
+    BadOpCode,
+    UnmaskedFrame,
+    BadControlFrame,
+    BadContinuation,
+    BadCloseFrame,
+    BadUtf8,
+    MessageTooLarge,
+}
+
+impl ProtocolError {
+    /// Code of the close frame sent for the error.
+    pub fn close_code(&self) -> u16 {
+        match self {
+            ProtocolError::BadUtf8 => close_code::INVALID_DATA,
+            ProtocolError::MessageTooLarge => close_code::MESSAGE_TOO_BIG,
+            _ => close_code::PROTOCOL_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadFrame => write!(f, "BadFrame"),
+            ProtocolError::BadOpCode => write!(f, "BadOpCode"),
+            ProtocolError::UnmaskedFrame => write!(f, "UnmaskedFrame"),
+            ProtocolError::BadControlFrame => write!(f, "BadControlFrame"),
+            ProtocolError::BadContinuation => write!(f, "BadContinuation"),
+            ProtocolError::BadCloseFrame => write!(f, "BadCloseFrame"),
+            ProtocolError::BadUtf8 => write!(f, "BadUtf8"),
+            ProtocolError::MessageTooLarge => write!(f, "MessageTooLarge"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for Error {
+    fn from(err: ProtocolError) -> Self {
+        Error::new(ErrorKind::Protocol, err)
+    }
+}
+
+/// Why a request can not be upgraded to a WebSocket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    NotWebSocket,
+    BadKey,
+    UnsupportedVersion,
+}
+
+impl HandshakeError {
+    /// The response that rejects the handshake.
+    pub fn response(&self) -> Response {
+        let mut resp = match self {
+            HandshakeError::NotWebSocket | HandshakeError::BadKey => {
+                Response::with_status(StatusCode::BAD_REQUEST)
+            }
+            // rfc6455 4.4, tell the client which version is spoken
+            HandshakeError::UnsupportedVersion => {
+                let mut resp = Response::with_status(StatusCode::UPGRADE_REQUIRED);
+                resp.header_map.set(SEC_WEBSOCKET_VERSION, VERSION);
+                resp
+            }
+        };
+        resp.header_map.set(headers::CONTENT_LENGTH, b"0");
+        resp
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::NotWebSocket => write!(f, "NotWebSocket"),
+            HandshakeError::BadKey => write!(f, "BadKey"),
+            HandshakeError::UnsupportedVersion => write!(f, "UnsupportedVersion"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Accept the opening handshake of a request, rfc6455 4.2. The handler
+/// answers with the returned response, and the WebSocket is ready once
+/// `OnWebSocket` resolves.
+pub fn accept(
+    req: &mut Request,
+    config: WebSocketConfig,
+) -> Result<(Response, OnWebSocket), HandshakeError> {
+    let has_token = |name: &[u8], token: &[u8]| {
+        req.header_map.get(name).is_some_and(|values| {
+            values
+                .iter()
+                .any(|h| header_values_contains_token(&h.value, token))
+        })
+    };
+
+    if !matches!(req.method, Method::GET)
+        || !has_token(headers::UPGRADE, WEBSOCKET)
+        || !has_token(headers::CONNECTION, headers::UPGRADE_TOKEN)
+    {
+        return Err(HandshakeError::NotWebSocket);
+    }
+
+    match req.header_map.get(SEC_WEBSOCKET_VERSION) {
+        Some([version]) if version.value.trim() == VERSION => {}
+        _ => return Err(HandshakeError::UnsupportedVersion),
+    }
+
+    let key = match req.header_map.get(SEC_WEBSOCKET_KEY) {
+        Some([key]) => key.value.trim(),
+        _ => return Err(HandshakeError::BadKey),
+    };
+    // a base64 encoded 16 byte nonce
+    if STANDARD.decode(key).map(|nonce| nonce.len()) != Ok(16) {
+        return Err(HandshakeError::BadKey);
+    }
+    let accept = accept_key(key);
+
+    let on_upgrade = upgrade::on(req).ok_or(HandshakeError::NotWebSocket)?;
+
+    let mut resp = Response::with_status(StatusCode::SWITCHING_PROTOCOLS);
+    resp.header_map.set(headers::UPGRADE, WEBSOCKET);
+    resp.header_map.set(headers::CONNECTION, headers::UPGRADE);
+    resp.header_map.set(SEC_WEBSOCKET_ACCEPT, accept.as_bytes());
+
+    Ok((resp, OnWebSocket { on_upgrade, config }))
+}
+
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key);
+    sha1.update(ACCEPT_GUID);
+
+    STANDARD.encode(sha1.digest().bytes())
+}
+
+/// Resolves to the WebSocket once the handshake response is sent.
+#[derive(Debug)]
+pub struct OnWebSocket {
+    on_upgrade: OnUpgrade,
+    config: WebSocketConfig,
+}
+
+impl Future for OnWebSocket {
+    type Output = Result<WebSocket, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let config = self.config.clone();
+
+        Pin::new(&mut self.on_upgrade)
+            .poll(cx)
+            .map(|ret| ret.map(|upgraded| WebSocket::from_raw(upgraded, config)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(b: u8) -> Result<Self, ProtocolError> {
+        let opcode = match b {
+            0x0 => OpCode::Continuation,
+            0x1 => OpCode::Text,
+            0x2 => OpCode::Binary,
+            0x8 => OpCode::Close,
+            0x9 => OpCode::Ping,
+            0xA => OpCode::Pong,
+            _ => return Err(ProtocolError::BadOpCode),
+        };
+
+        Ok(opcode)
+    }
+
+    fn as_u8(&self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+
+    fn is_control(&self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+#[derive(Debug)]
+struct FrameHeader {
+    fin: bool,
+    opcode: OpCode,
+    mask: [u8; 4],
+    len: usize,
+}
+
+// rfc6455 5.2, frames from a client are always masked
+fn parse_frame_header(buf: &[u8]) -> Result<Option<(usize, FrameHeader)>, ProtocolError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    // no extension is negotiated, so the reserved bits are never set
+    if buf[0] & 0x70 != 0 {
+        return Err(ProtocolError::BadFrame);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = OpCode::from_u8(buf[0] & 0x0F)?;
+
+    if buf[1] & 0x80 == 0 {
+        return Err(ProtocolError::UnmaskedFrame);
+    }
+
+    let (mut pos, len) = match buf[1] & 0x7F {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            (4, u16::from_be_bytes([buf[2], buf[3]]) as u64)
+        }
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&buf[2..10]);
+            let len = u64::from_be_bytes(b);
+            // the most significant bit must be 0
+            if len >> 63 != 0 {
+                return Err(ProtocolError::BadFrame);
+            }
+            (10, len)
+        }
+        len => (2, len as u64),
+    };
+
+    if opcode.is_control() && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+        return Err(ProtocolError::BadControlFrame);
+    }
+
+    if buf.len() < pos + 4 {
+        return Ok(None);
+    }
+    let mut mask = [0u8; 4];
+    mask.copy_from_slice(&buf[pos..pos + 4]);
+    pos += 4;
+
+    let len = usize::try_from(len).map_err(|_e| ProtocolError::MessageTooLarge)?;
+
+    Ok(Some((
+        pos,
+        FrameHeader {
+            fin,
+            opcode,
+            mask,
+            len,
+        },
+    )))
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+/// Server side of a WebSocket connection.
+pub struct WebSocket<S = Upgraded> {
+    stream: S,
+    buffer: BytesMut,
+    config: WebSocketConfig,
+    // opcode and data of a fragmented message
+    partial: Option<(OpCode, BytesMut)>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<S> fmt::Debug for WebSocket<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocket")
+            .field("config", &self.config)
+            .field("close_sent", &self.close_sent)
+            .field("close_received", &self.close_received)
+            .finish()
+    }
+}
+
+impl<S> WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Speak the protocol over a connection that already did the handshake.
+    pub fn from_raw(stream: S, config: WebSocketConfig) -> Self {
+        WebSocket {
+            stream,
+            buffer: BytesMut::with_capacity(DEFAULT_BUFFER_CAPACITY),
+            config,
+            partial: None,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// Receive the next message, None once the close handshake is done.
+    /// Pings are answered before they are returned, a close frame is
+    /// echoed when it was not sent first. A protocol error closes the
+    /// connection with the matching code.
+    ///
+    /// Cancel safe while waiting for data, a partly received message is
+    /// kept for the next call.
+    pub async fn recv(&mut self) -> Result<Option<Message>, Error> {
+        while !self.close_received {
+            let ret = match self.read_frame().await {
+                Ok((header, payload)) => self.on_frame(header, payload).await,
+                Err(err) => Err(err),
+            };
+
+            match ret {
+                Ok(Some(msg)) => return Ok(Some(msg)),
+                Ok(None) => {}
+                Err(err) => {
+                    if let Some(code) = err.websocket_error().map(|e| e.close_code()) {
+                        let _ = self.send_close(Some(code), "").await;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub async fn send(&mut self, msg: Message) -> Result<(), Error> {
+        if self.close_sent {
+            return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "").into());
+        }
+
+        match msg {
+            Message::Text(text) => self.send_data(OpCode::Text, text.as_bytes()).await,
+            Message::Binary(data) => self.send_data(OpCode::Binary, &data).await,
+            Message::Ping(data) => self.send_control(OpCode::Ping, &data).await,
+            Message::Pong(data) => self.send_control(OpCode::Pong, &data).await,
+            Message::Close(frame) => match frame {
+                Some(frame) => self.send_close(Some(frame.code), &frame.reason).await,
+                None => self.send_close(None, "").await,
+            },
+        }
+    }
+
+    /// Start the close handshake, `recv` returns None once the peer answered.
+    pub async fn close(&mut self, frame: Option<CloseFrame>) -> Result<(), Error> {
+        self.send(Message::Close(frame)).await
+    }
+
+    async fn read_frame(&mut self) -> Result<(FrameHeader, BytesMut), Error> {
+        loop {
+            if let Some((header_len, header)) = parse_frame_header(&self.buffer)? {
+                let partial = self.partial.as_ref().map_or(0, |(_, data)| data.len());
+                if header.len.saturating_add(partial) > self.config.max_message_size {
+                    return Err(ProtocolError::MessageTooLarge.into());
+                }
+
+                if self.buffer.len() >= header_len + header.len {
+                    self.buffer.advance(header_len);
+                    let mut payload = self.buffer.split_to(header.len);
+                    apply_mask(&mut payload, header.mask);
+
+                    return Ok((header, payload));
+                }
+            }
+
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "").into());
+            }
+        }
+    }
+
+    async fn on_frame(
+        &mut self,
+        header: FrameHeader,
+        payload: BytesMut,
+    ) -> Result<Option<Message>, Error> {
+        let (opcode, data) = match header.opcode {
+            OpCode::Text | OpCode::Binary => {
+                if self.partial.is_some() {
+                    return Err(ProtocolError::BadContinuation.into());
+                }
+                if !header.fin {
+                    self.partial = Some((header.opcode, payload));
+                    return Ok(None);
+                }
+                (header.opcode, payload)
+            }
+            OpCode::Continuation => {
+                let (opcode, mut data) =
+                    self.partial.take().ok_or(ProtocolError::BadContinuation)?;
+                data.put_slice(&payload);
+                if !header.fin {
+                    self.partial = Some((opcode, data));
+                    return Ok(None);
+                }
+                (opcode, data)
+            }
+            OpCode::Ping => {
+                let data = payload.freeze();
+                if !self.close_sent {
+                    self.send_control(OpCode::Pong, &data).await?;
+                }
+                return Ok(Some(Message::Ping(data)));
+            }
+            OpCode::Pong => return Ok(Some(Message::Pong(payload.freeze()))),
+            OpCode::Close => {
+                let frame = parse_close_payload(&payload)?;
+                self.close_received = true;
+
+                if !self.close_sent {
+                    let code = frame.as_ref().map(|frame| frame.code);
+                    self.send_close(code, "").await?;
+                }
+
+                return Ok(Some(Message::Close(frame)));
+            }
+        };
+
+        let msg = match opcode {
+            OpCode::Text => {
+                let text = String::from_utf8(data.to_vec()).map_err(|_e| ProtocolError::BadUtf8)?;
+                Message::Text(text)
+            }
+            _ => Message::Binary(data.freeze()),
+        };
+
+        Ok(Some(msg))
+    }
+
+    async fn send_data(&mut self, opcode: OpCode, data: &[u8]) -> Result<(), Error> {
+        let mut chunks = data.chunks(self.config.max_frame_size).peekable();
+
+        // an empty message is still one frame
+        if chunks.peek().is_none() {
+            return self.write_frame(true, opcode, b"").await;
+        }
+
+        let mut opcode = opcode;
+        while let Some(chunk) = chunks.next() {
+            self.write_frame(chunks.peek().is_none(), opcode, chunk)
+                .await?;
+            opcode = OpCode::Continuation;
+        }
+
+        Ok(())
+    }
+
+    async fn send_control(&mut self, opcode: OpCode, data: &[u8]) -> Result<(), Error> {
+        if data.len() > MAX_CONTROL_PAYLOAD {
+            return Err(ProtocolError::BadControlFrame.into());
+        }
+
+        self.write_frame(true, opcode, data).await
+    }
+
+    async fn send_close(&mut self, code: Option<u16>, reason: &str) -> Result<(), Error> {
+        if self.close_sent {
+            return Ok(());
+        }
+
+        let mut payload = BytesMut::new();
+        if let Some(code) = code {
+            payload.put_u16(code);
+            payload.put_slice(reason.as_bytes());
+        }
+
+        self.send_control(OpCode::Close, &payload).await?;
+        self.close_sent = true;
+
+        // rfc6455 7.1.1, the server closes the connection first
+        if self.close_received {
+            self.stream.shutdown().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_frame(&mut self, fin: bool, opcode: OpCode, data: &[u8]) -> Result<(), Error> {
+        let mut buf = BytesMut::with_capacity(data.len() + 10);
+
+        buf.put_u8(((fin as u8) << 7) | opcode.as_u8());
+
+        // frames from a server are never masked
+        match data.len() {
+            len if len < 126 => buf.put_u8(len as u8),
+            len if len <= u16::MAX as usize => {
+                buf.put_u8(126);
+                buf.put_u16(len as u16);
+            }
+            len => {
+                buf.put_u8(127);
+                buf.put_u64(len as u64);
+            }
+        }
+        buf.put_slice(data);
+
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+}
+
+// rfc6455 5.5.1, an optional status code followed by a UTF-8 reason
+fn parse_close_payload(payload: &[u8]) -> Result<Option<CloseFrame>, ProtocolError> {
+    match payload.len() {
+        0 => return Ok(None),
+        1 => return Err(ProtocolError::BadCloseFrame),
+        _ => {}
+    }
+
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    if !close_code::is_valid(code) {
+        return Err(ProtocolError::BadCloseFrame);
+    }
+
+    let reason = std::str::from_utf8(&payload[2..]).map_err(|_e| ProtocolError::BadUtf8)?;
+
+    Ok(Some(CloseFrame {
+        code,
+        reason: reason.to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::server::serve;
+
+    /// A masked frame as a client sends it.
+    fn client_frame(first: u8, data: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+
+        let mut buf = vec![first];
+        match data.len() {
+            len if len < 126 => buf.push(0x80 | len as u8),
+            len => {
+                buf.push(0x80 | 126);
+                buf.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        buf.extend_from_slice(&mask);
+
+        let mut data = data.to_vec();
+        apply_mask(&mut data, mask);
+        buf.extend_from_slice(&data);
+        buf
+    }
+
+    async fn read_server_frame(client: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        client.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[1] & 0x80, 0, "server frames are not masked");
+
+        let len = match head[1] {
+            126 => client.read_u16().await.unwrap() as usize,
+            127 => client.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+
+        let mut data = vec![0u8; len];
+        client.read_exact(&mut data).await.unwrap();
+        (head[0], data)
+    }
+
+    fn websocket(config: WebSocketConfig) -> (DuplexStream, WebSocket<DuplexStream>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+
+        (client, WebSocket::from_raw(server, config))
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // rfc6455 1.3
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_parse_frame_header() {
+        let frame = client_frame(0x81, b"hi");
+        let (len, header) = parse_frame_header(&frame).unwrap().unwrap();
+        assert_eq!(len, 6);
+        assert!(header.fin);
+        assert_eq!(header.opcode, OpCode::Text);
+        assert_eq!(header.len, 2);
+
+        assert!(parse_frame_header(&frame[..5]).unwrap().is_none());
+
+        assert_eq!(
+            parse_frame_header(&[0x81, 0x02, b'h', b'i']).unwrap_err(),
+            ProtocolError::UnmaskedFrame
+        );
+        assert_eq!(
+            parse_frame_header(&client_frame(0xC1, b"hi")).unwrap_err(),
+            ProtocolError::BadFrame
+        );
+        assert_eq!(
+            parse_frame_header(&client_frame(0x83, b"")).unwrap_err(),
+            ProtocolError::BadOpCode
+        );
+        // fragmented control frame
+        assert_eq!(
+            parse_frame_header(&client_frame(0x09, b"")).unwrap_err(),
+            ProtocolError::BadControlFrame
+        );
+        assert_eq!(
+            parse_frame_header(&client_frame(0x89, &[0u8; 126])).unwrap_err(),
+            ProtocolError::BadControlFrame
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_message() {
+        let (mut client, mut ws) = websocket(WebSocketConfig::default());
+
+        let mut data = client_frame(0x01, b"hel");
+        // control frames may come between fragments
+        data.extend(client_frame(0x89, b"p"));
+        data.extend(client_frame(0x80, b"lo"));
+        client.write_all(&data).await.unwrap();
+
+        assert_eq!(
+            ws.recv().await.unwrap(),
+            Some(Message::Ping(Bytes::from("p")))
+        );
+        assert_eq!(
+            ws.recv().await.unwrap(),
+            Some(Message::Text("hello".to_string()))
+        );
+        assert_eq!(read_server_frame(&mut client).await, (0x8A, b"p".to_vec()));
+
+        // sent in frames of max_frame_size
+        let (mut client, mut ws) = websocket(WebSocketConfig::builder().max_frame_size(4).build());
+        ws.send(Message::Binary(Bytes::from("0123456789")))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            read_server_frame(&mut client).await,
+            (0x02, b"0123".to_vec())
+        );
+        assert_eq!(
+            read_server_frame(&mut client).await,
+            (0x00, b"4567".to_vec())
+        );
+        assert_eq!(read_server_frame(&mut client).await, (0x80, b"89".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_close_handshake() {
+        let (mut client, mut ws) = websocket(WebSocketConfig::default());
+
+        let mut payload = close_code::GOING_AWAY.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        client
+            .write_all(&client_frame(0x88, &payload))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            ws.recv().await.unwrap(),
+            Some(Message::Close(Some(CloseFrame {
+                code: close_code::GOING_AWAY,
+                reason: "bye".to_string(),
+            })))
+        );
+        assert_eq!(ws.recv().await.unwrap(), None);
+        assert!(ws.send(Message::Text("late".to_string())).await.is_err());
+
+        // the code is echoed and the connection closed
+        let (_, payload) = read_server_frame(&mut client).await;
+        assert_eq!(payload, close_code::GOING_AWAY.to_be_bytes());
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() {
+        let cases: [(WebSocketConfig, Vec<u8>, u16); 4] = [
+            (
+                WebSocketConfig::default(),
+                client_frame(0x80, b"x"),
+                close_code::PROTOCOL_ERROR,
+            ),
+            (
+                WebSocketConfig::default(),
+                client_frame(0x81, b"\xff"),
+                close_code::INVALID_DATA,
+            ),
+            (
+                WebSocketConfig::builder().max_message_size(4).build(),
+                [client_frame(0x02, b"012"), client_frame(0x80, b"34")].concat(),
+                close_code::MESSAGE_TOO_BIG,
+            ),
+            (
+                WebSocketConfig::default(),
+                client_frame(0x88, &[0x03, 0xEC]),
+                close_code::PROTOCOL_ERROR,
+            ),
+        ];
+
+        for (config, data, code) in cases {
+            let (mut client, mut ws) = websocket(config);
+            client.write_all(&data).await.unwrap();
+
+            assert!(ws.recv().await.is_err());
+
+            let (first, payload) = read_server_frame(&mut client).await;
+            assert_eq!(first, 0x88);
+            assert_eq!(payload, code.to_be_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        tokio::spawn(serve(server, |mut req: Request| {
+            Box::pin(async move {
+                match accept(&mut req, WebSocketConfig::default()) {
+                    Ok((resp, on_websocket)) => {
+                        tokio::spawn(async move {
+                            let mut ws = on_websocket.await.unwrap();
+                            while let Some(msg) = ws.recv().await.unwrap() {
+                                if let Message::Text(_) = msg {
+                                    ws.send(msg).await.unwrap();
+                                }
+                            }
+                        });
+                        resp
+                    }
+                    Err(err) => err.response(),
+                }
+            })
+        }));
+
+        // a client that is not a WebSocket one
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\
+                Sec-WebSocket-Version: 8\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 426 "), "{}", head);
+        assert!(head.contains("Sec-WebSocket-Version: 13\r\n"), "{}", head);
+
+        client
+            .write_all(
+                b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\n\
+                upgrade: WebSocket\r\nconnection: keep-alive, Upgrade\r\n\
+                sec-websocket-version: 13\r\nsec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        assert!(
+            head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+            "{}",
+            head
+        );
+
+        client
+            .write_all(&client_frame(0x81, b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_server_frame(&mut client).await,
+            (0x81, b"hello".to_vec())
+        );
+    }
+
+    async fn read_head(stream: &mut DuplexStream) -> String {
+        let mut buf = Vec::new();
+
+        while !buf.ends_with(b"\r\n\r\n") {
+            buf.push(stream.read_u8().await.unwrap());
+        }
+
+        String::from_utf8(buf).unwrap()
+    }
+}